@@ -0,0 +1,93 @@
+//! Bosses by name.
+//!
+//! The game only keeps a main boss, mini boss and torizo bit per area, this
+//! maps the actual bosses onto those bits so nobody has to remember that
+//! Norfair's mini boss is Crocomire.
+
+use crate::*;
+use std::collections::BTreeSet;
+
+#[derive(
+    clap::ArgEnum, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum NamedBoss {
+    BombTorizo,
+    Kraid,
+    SporeSpawn,
+    Ridley,
+    Crocomire,
+    GoldenTorizo,
+    Phantoon,
+    Draygon,
+    Botwoon,
+    MotherBrain,
+    CeresRidley,
+}
+
+pub const NAMED_BOSSES: [NamedBoss; 11] = [
+    NamedBoss::BombTorizo,
+    NamedBoss::Kraid,
+    NamedBoss::SporeSpawn,
+    NamedBoss::Ridley,
+    NamedBoss::Crocomire,
+    NamedBoss::GoldenTorizo,
+    NamedBoss::Phantoon,
+    NamedBoss::Draygon,
+    NamedBoss::Botwoon,
+    NamedBoss::MotherBrain,
+    NamedBoss::CeresRidley,
+];
+
+impl NamedBoss {
+    // Where the game keeps this boss' defeated bit
+    pub fn location(self) -> (Area, Boss) {
+        match self {
+            NamedBoss::BombTorizo => (Area::Crateria, Boss::Torizo),
+            NamedBoss::Kraid => (Area::Brinstar, Boss::MainBoss),
+            NamedBoss::SporeSpawn => (Area::Brinstar, Boss::MiniBoss),
+            NamedBoss::Ridley => (Area::Norfair, Boss::MainBoss),
+            NamedBoss::Crocomire => (Area::Norfair, Boss::MiniBoss),
+            NamedBoss::GoldenTorizo => (Area::Norfair, Boss::Torizo),
+            NamedBoss::Phantoon => (Area::WreckedShip, Boss::MainBoss),
+            NamedBoss::Draygon => (Area::Maridia, Boss::MainBoss),
+            NamedBoss::Botwoon => (Area::Maridia, Boss::MiniBoss),
+            NamedBoss::MotherBrain => (Area::Tourian, Boss::MainBoss),
+            NamedBoss::CeresRidley => (Area::Ceres, Boss::MainBoss),
+        }
+    }
+
+    pub fn from_location(area: Area, boss: Boss) -> Option<NamedBoss> {
+        NAMED_BOSSES
+            .iter()
+            .copied()
+            .find(|b| b.location() == (area, boss))
+    }
+}
+
+impl Samus {
+    pub fn is_defeated(&self, boss: NamedBoss) -> bool {
+        let (area, bit) = boss.location();
+        self.bosses
+            .get(&area)
+            .map(|bosses| bosses.contains(&bit))
+            .unwrap_or(false)
+    }
+
+    pub fn set_defeated(&mut self, boss: NamedBoss, defeated: bool) {
+        let (area, bit) = boss.location();
+        let bosses = self.bosses.entry(area).or_default();
+        if defeated {
+            bosses.insert(bit);
+        } else {
+            bosses.remove(&bit);
+        }
+    }
+
+    pub fn defeated(&self) -> BTreeSet<NamedBoss> {
+        NAMED_BOSSES
+            .iter()
+            .copied()
+            .filter(|b| self.is_defeated(*b))
+            .collect()
+    }
+}
@@ -0,0 +1,1325 @@
+//! A small 65816 interpreter over an in-memory bus.
+//!
+//! This is not a cycle accurate emulator. It is just enough of the CPU to run
+//! the payloads we upload to the CMD space from the preamble to the final
+//! `jmp ($ffea)` and then look at what ended up in WRAM.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+pub const FLAG_C: u8 = 0x01;
+pub const FLAG_Z: u8 = 0x02;
+pub const FLAG_I: u8 = 0x04;
+pub const FLAG_D: u8 = 0x08;
+pub const FLAG_X: u8 = 0x10;
+pub const FLAG_M: u8 = 0x20;
+pub const FLAG_V: u8 = 0x40;
+pub const FLAG_N: u8 = 0x80;
+
+// Where the FXPak maps the CMD space. The NMI hook jumps here.
+pub const CMD_ADDR: u32 = 0x00_2C00;
+// Give up after this many instructions, our payloads are all straight line
+// code so anything this long is a runaway loop.
+pub const MAX_STEPS: usize = 100_000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Op {
+    Adc,
+    And,
+    Asl,
+    Bcc,
+    Bcs,
+    Beq,
+    Bit,
+    Bmi,
+    Bne,
+    Bpl,
+    Bra,
+    Brk,
+    Brl,
+    Bvc,
+    Bvs,
+    Clc,
+    Cld,
+    Cli,
+    Clv,
+    Cmp,
+    Cop,
+    Cpx,
+    Cpy,
+    Dec,
+    Dex,
+    Dey,
+    Eor,
+    Inc,
+    Inx,
+    Iny,
+    Jml,
+    Jmp,
+    Jsl,
+    Jsr,
+    Lda,
+    Ldx,
+    Ldy,
+    Lsr,
+    Mvn,
+    Mvp,
+    Nop,
+    Ora,
+    Pea,
+    Pei,
+    Per,
+    Pha,
+    Phb,
+    Phd,
+    Phk,
+    Php,
+    Phx,
+    Phy,
+    Pla,
+    Plb,
+    Pld,
+    Plp,
+    Plx,
+    Ply,
+    Rep,
+    Rol,
+    Ror,
+    Rti,
+    Rtl,
+    Rts,
+    Sbc,
+    Sec,
+    Sed,
+    Sei,
+    Sep,
+    Sta,
+    Stp,
+    Stx,
+    Sty,
+    Stz,
+    Tax,
+    Tay,
+    Tcd,
+    Tcs,
+    Tdc,
+    Trb,
+    Tsb,
+    Tsc,
+    Tsx,
+    Txa,
+    Txs,
+    Txy,
+    Tya,
+    Tyx,
+    Wai,
+    Wdm,
+    Xba,
+    Xce,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+    Implied,
+    Accumulator,
+    // Immediate operand sized by the M flag
+    ImmediateM,
+    // Immediate operand sized by the X flag
+    ImmediateX,
+    Immediate8,
+    Immediate16,
+    Relative8,
+    Relative16,
+    Direct,
+    DirectX,
+    DirectY,
+    DirectIndirect,
+    DirectIndirectX,
+    DirectIndirectY,
+    DirectIndirectLong,
+    DirectIndirectLongY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    AbsoluteLong,
+    AbsoluteLongX,
+    AbsoluteIndirect,
+    AbsoluteIndirectX,
+    AbsoluteIndirectLong,
+    StackRelative,
+    StackRelativeIndirectY,
+    BlockMove,
+}
+
+impl Mode {
+    // Number of operand bytes following the opcode
+    pub fn operand_len(&self, m8: bool, x8: bool) -> usize {
+        use Mode::*;
+        match self {
+            Implied | Accumulator => 0,
+            ImmediateM => 2 - m8 as usize,
+            ImmediateX => 2 - x8 as usize,
+            Immediate8
+            | Relative8
+            | Direct
+            | DirectX
+            | DirectY
+            | DirectIndirect
+            | DirectIndirectX
+            | DirectIndirectY
+            | DirectIndirectLong
+            | DirectIndirectLongY
+            | StackRelative
+            | StackRelativeIndirectY => 1,
+            Immediate16 | Relative16 | Absolute | AbsoluteX | AbsoluteY | AbsoluteIndirect
+            | AbsoluteIndirectX | AbsoluteIndirectLong | BlockMove => 2,
+            AbsoluteLong | AbsoluteLongX => 3,
+        }
+    }
+}
+
+pub fn decode(opcode: u8) -> (Op, Mode) {
+    use Mode::*;
+    use Op::*;
+    match opcode {
+        0x00 => (Brk, Immediate8),
+        0x02 => (Cop, Immediate8),
+        0x04 => (Tsb, Direct),
+        0x06 => (Asl, Direct),
+        0x08 => (Php, Implied),
+        0x0A => (Asl, Accumulator),
+        0x0B => (Phd, Implied),
+        0x0C => (Tsb, Absolute),
+        0x0E => (Asl, Absolute),
+        0x10 => (Bpl, Relative8),
+        0x14 => (Trb, Direct),
+        0x16 => (Asl, DirectX),
+        0x18 => (Clc, Implied),
+        0x1A => (Inc, Accumulator),
+        0x1B => (Tcs, Implied),
+        0x1C => (Trb, Absolute),
+        0x1E => (Asl, AbsoluteX),
+        0x20 => (Jsr, Absolute),
+        0x22 => (Jsl, AbsoluteLong),
+        0x24 => (Bit, Direct),
+        0x26 => (Rol, Direct),
+        0x28 => (Plp, Implied),
+        0x2A => (Rol, Accumulator),
+        0x2B => (Pld, Implied),
+        0x2C => (Bit, Absolute),
+        0x2E => (Rol, Absolute),
+        0x30 => (Bmi, Relative8),
+        0x34 => (Bit, DirectX),
+        0x36 => (Rol, DirectX),
+        0x38 => (Sec, Implied),
+        0x3A => (Dec, Accumulator),
+        0x3B => (Tsc, Implied),
+        0x3C => (Bit, AbsoluteX),
+        0x3E => (Rol, AbsoluteX),
+        0x40 => (Rti, Implied),
+        0x42 => (Wdm, Immediate8),
+        0x44 => (Mvp, BlockMove),
+        0x46 => (Lsr, Direct),
+        0x48 => (Pha, Implied),
+        0x4A => (Lsr, Accumulator),
+        0x4B => (Phk, Implied),
+        0x4C => (Jmp, Absolute),
+        0x4E => (Lsr, Absolute),
+        0x50 => (Bvc, Relative8),
+        0x54 => (Mvn, BlockMove),
+        0x56 => (Lsr, DirectX),
+        0x58 => (Cli, Implied),
+        0x5A => (Phy, Implied),
+        0x5B => (Tcd, Implied),
+        0x5C => (Jml, AbsoluteLong),
+        0x5E => (Lsr, AbsoluteX),
+        0x60 => (Rts, Implied),
+        0x62 => (Per, Relative16),
+        0x64 => (Stz, Direct),
+        0x66 => (Ror, Direct),
+        0x68 => (Pla, Implied),
+        0x6A => (Ror, Accumulator),
+        0x6B => (Rtl, Implied),
+        0x6C => (Jmp, AbsoluteIndirect),
+        0x6E => (Ror, Absolute),
+        0x70 => (Bvs, Relative8),
+        0x74 => (Stz, DirectX),
+        0x76 => (Ror, DirectX),
+        0x78 => (Sei, Implied),
+        0x7A => (Ply, Implied),
+        0x7B => (Tdc, Implied),
+        0x7C => (Jmp, AbsoluteIndirectX),
+        0x7E => (Ror, AbsoluteX),
+        0x80 => (Bra, Relative8),
+        0x82 => (Brl, Relative16),
+        0x84 => (Sty, Direct),
+        0x86 => (Stx, Direct),
+        0x88 => (Dey, Implied),
+        0x89 => (Bit, ImmediateM),
+        0x8A => (Txa, Implied),
+        0x8B => (Phb, Implied),
+        0x8C => (Sty, Absolute),
+        0x8E => (Stx, Absolute),
+        0x90 => (Bcc, Relative8),
+        0x94 => (Sty, DirectX),
+        0x96 => (Stx, DirectY),
+        0x98 => (Tya, Implied),
+        0x9A => (Txs, Implied),
+        0x9B => (Txy, Implied),
+        0x9C => (Stz, Absolute),
+        0x9E => (Stz, AbsoluteX),
+        0xA0 => (Ldy, ImmediateX),
+        0xA2 => (Ldx, ImmediateX),
+        0xA4 => (Ldy, Direct),
+        0xA6 => (Ldx, Direct),
+        0xA8 => (Tay, Implied),
+        0xAA => (Tax, Implied),
+        0xAB => (Plb, Implied),
+        0xAC => (Ldy, Absolute),
+        0xAE => (Ldx, Absolute),
+        0xB0 => (Bcs, Relative8),
+        0xB4 => (Ldy, DirectX),
+        0xB6 => (Ldx, DirectY),
+        0xB8 => (Clv, Implied),
+        0xBA => (Tsx, Implied),
+        0xBB => (Tyx, Implied),
+        0xBC => (Ldy, AbsoluteX),
+        0xBE => (Ldx, AbsoluteY),
+        0xC0 => (Cpy, ImmediateX),
+        0xC2 => (Rep, Immediate8),
+        0xC4 => (Cpy, Direct),
+        0xC6 => (Dec, Direct),
+        0xC8 => (Iny, Implied),
+        0xCA => (Dex, Implied),
+        0xCB => (Wai, Implied),
+        0xCC => (Cpy, Absolute),
+        0xCE => (Dec, Absolute),
+        0xD0 => (Bne, Relative8),
+        0xD4 => (Pei, DirectIndirect),
+        0xD6 => (Dec, DirectX),
+        0xD8 => (Cld, Implied),
+        0xDA => (Phx, Implied),
+        0xDB => (Stp, Implied),
+        0xDC => (Jml, AbsoluteIndirectLong),
+        0xDE => (Dec, AbsoluteX),
+        0xE0 => (Cpx, ImmediateX),
+        0xE2 => (Sep, Immediate8),
+        0xE4 => (Cpx, Direct),
+        0xE6 => (Inc, Direct),
+        0xE8 => (Inx, Implied),
+        0xEA => (Nop, Implied),
+        0xEB => (Xba, Implied),
+        0xEC => (Cpx, Absolute),
+        0xEE => (Inc, Absolute),
+        0xF0 => (Beq, Relative8),
+        0xF4 => (Pea, Immediate16),
+        0xF6 => (Inc, DirectX),
+        0xF8 => (Sed, Implied),
+        0xFA => (Plx, Implied),
+        0xFB => (Xce, Implied),
+        0xFC => (Jsr, AbsoluteIndirectX),
+        0xFE => (Inc, AbsoluteX),
+        _ => {
+            // Everything left over is one of the eight accumulator ops laid
+            // out in the regular columns of the opcode matrix
+            let op = [Ora, And, Eor, Adc, Sta, Lda, Cmp, Sbc][(opcode >> 5) as usize];
+            let odd_row = opcode & 0x10 != 0;
+            let mode = match (odd_row, opcode & 0x0F) {
+                (false, 0x1) => DirectIndirectX,
+                (false, 0x3) => StackRelative,
+                (false, 0x5) => Direct,
+                (false, 0x7) => DirectIndirectLong,
+                (false, 0x9) => ImmediateM,
+                (false, 0xD) => Absolute,
+                (false, 0xF) => AbsoluteLong,
+                (true, 0x1) => DirectIndirectY,
+                (true, 0x2) => DirectIndirect,
+                (true, 0x3) => StackRelativeIndirectY,
+                (true, 0x5) => DirectX,
+                (true, 0x7) => DirectIndirectLongY,
+                (true, 0x9) => AbsoluteY,
+                (true, 0xD) => AbsoluteX,
+                (true, 0xF) => AbsoluteLongX,
+                _ => unreachable!("opcode {:02x} not covered", opcode),
+            };
+            (op, mode)
+        }
+    }
+}
+
+pub trait Bus {
+    fn read(&mut self, address: u32) -> u8;
+    fn write(&mut self, address: u32, data: u8);
+}
+
+// WRAM plus a sparse map for everything else (registers, CMD space, ROM).
+// Low WRAM and the system area are mirrored into banks $00-$3F and $80-$BF
+// like on the console, so `sta $09C2` lands in the same place as
+// `sta $7E09C2` whatever the data bank is.
+#[derive(Debug, Clone)]
+pub struct MemoryBus {
+    pub wram: Vec<u8>,
+    pub other: HashMap<u32, u8>,
+}
+
+impl Default for MemoryBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBus {
+    pub fn new() -> MemoryBus {
+        MemoryBus {
+            wram: vec![0; 0x2_0000],
+            other: HashMap::new(),
+        }
+    }
+
+    fn wram_offset(address: u32) -> Option<usize> {
+        let bank = (address >> 16) & 0xFF;
+        let offset = address & 0xFFFF;
+        match bank {
+            0x7E | 0x7F => Some((address - 0x7E_0000) as usize),
+            0x00..=0x3F | 0x80..=0xBF if offset < 0x2000 => Some(offset as usize),
+            _ => None,
+        }
+    }
+
+    // Banks $00-$3F and $80-$BF share the same system area below $8000
+    // (WRAM mirror, registers, CMD space), fold them all onto bank $00.
+    fn system_address(address: u32) -> u32 {
+        let bank = (address >> 16) & 0xFF;
+        match bank {
+            0x00..=0x3F | 0x80..=0xBF if address & 0xFFFF < 0x8000 => address & 0xFFFF,
+            _ => address,
+        }
+    }
+
+    pub fn load(&mut self, address: u32, data: &[u8]) {
+        for (i, b) in data.iter().enumerate() {
+            self.write(address + i as u32, *b);
+        }
+    }
+
+    pub fn read_u16(&mut self, address: u32) -> u16 {
+        u16::from_le_bytes([self.read(address), self.read(address + 1)])
+    }
+}
+
+impl Bus for MemoryBus {
+    fn read(&mut self, address: u32) -> u8 {
+        let address = address & 0xFF_FFFF;
+        match MemoryBus::wram_offset(address) {
+            Some(offset) => self.wram[offset],
+            None => *self
+                .other
+                .get(&MemoryBus::system_address(address))
+                .unwrap_or(&0),
+        }
+    }
+
+    fn write(&mut self, address: u32, data: u8) {
+        let address = address & 0xFF_FFFF;
+        match MemoryBus::wram_offset(address) {
+            Some(offset) => self.wram[offset] = data,
+            None => {
+                self.other.insert(MemoryBus::system_address(address), data);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cpu {
+    pub a: u16,
+    pub x: u16,
+    pub y: u16,
+    pub s: u16,
+    pub d: u16,
+    pub db: u8,
+    pub pb: u8,
+    pub pc: u16,
+    pub p: u8,
+    pub e: bool,
+}
+
+// How an operand was resolved: either a value baked into the instruction or
+// a 24-bit address into the bus.
+#[derive(Debug, Copy, Clone)]
+enum Operand {
+    None,
+    Value(u16),
+    Address(u32),
+}
+
+impl Cpu {
+    // CPU state roughly as the game leaves it when the NMI hook jumps to the
+    // CMD space: native mode, 8-bit registers and the data bank at $80.
+    pub fn nmi_entry() -> Cpu {
+        Cpu {
+            a: 0,
+            x: 0,
+            y: 0,
+            s: 0x1FF0,
+            d: 0,
+            db: 0x80,
+            pb: (CMD_ADDR >> 16) as u8,
+            pc: CMD_ADDR as u16,
+            p: FLAG_M | FLAG_X | FLAG_I,
+            e: false,
+        }
+    }
+
+    pub fn m8(&self) -> bool {
+        self.e || self.p & FLAG_M != 0
+    }
+
+    pub fn x8(&self) -> bool {
+        self.e || self.p & FLAG_X != 0
+    }
+
+    pub fn pc_long(&self) -> u32 {
+        ((self.pb as u32) << 16) | self.pc as u32
+    }
+
+    fn flag(&self, flag: u8) -> bool {
+        self.p & flag != 0
+    }
+
+    fn set_flag(&mut self, flag: u8, on: bool) {
+        if on {
+            self.p |= flag;
+        } else {
+            self.p &= !flag;
+        }
+    }
+
+    fn set_p(&mut self, p: u8) {
+        self.p = p;
+        if self.e {
+            self.p |= FLAG_M | FLAG_X;
+        }
+        if self.x8() {
+            self.x &= 0xFF;
+            self.y &= 0xFF;
+        }
+    }
+
+    fn set_nz(&mut self, value: u16, eight: bool) {
+        if eight {
+            self.set_flag(FLAG_Z, value & 0xFF == 0);
+            self.set_flag(FLAG_N, value & 0x80 != 0);
+        } else {
+            self.set_flag(FLAG_Z, value == 0);
+            self.set_flag(FLAG_N, value & 0x8000 != 0);
+        }
+    }
+
+    fn fetch<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let b = bus.read(self.pc_long());
+        self.pc = self.pc.wrapping_add(1);
+        b
+    }
+
+    fn fetch_u16<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        let lo = self.fetch(bus) as u16;
+        let hi = self.fetch(bus) as u16;
+        (hi << 8) | lo
+    }
+
+    fn fetch_u24<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        let lo = self.fetch_u16(bus) as u32;
+        let hi = self.fetch(bus) as u32;
+        (hi << 16) | lo
+    }
+
+    fn read_u16<B: Bus>(bus: &mut B, address: u32) -> u16 {
+        let lo = bus.read(address) as u16;
+        let hi = bus.read((address + 1) & 0xFF_FFFF) as u16;
+        (hi << 8) | lo
+    }
+
+    fn read_u24<B: Bus>(bus: &mut B, address: u32) -> u32 {
+        let lo = Cpu::read_u16(bus, address) as u32;
+        let hi = bus.read((address + 2) & 0xFF_FFFF) as u32;
+        (hi << 16) | lo
+    }
+
+    fn read_sized<B: Bus>(bus: &mut B, address: u32, eight: bool) -> u16 {
+        if eight {
+            bus.read(address) as u16
+        } else {
+            Cpu::read_u16(bus, address)
+        }
+    }
+
+    fn write_sized<B: Bus>(bus: &mut B, address: u32, value: u16, eight: bool) {
+        bus.write(address, value as u8);
+        if !eight {
+            bus.write((address + 1) & 0xFF_FFFF, (value >> 8) as u8);
+        }
+    }
+
+    fn push<B: Bus>(&mut self, bus: &mut B, data: u8) {
+        bus.write(self.s as u32, data);
+        self.s = self.s.wrapping_sub(1);
+        if self.e {
+            self.s = 0x0100 | (self.s & 0xFF);
+        }
+    }
+
+    fn pull<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        self.s = self.s.wrapping_add(1);
+        if self.e {
+            self.s = 0x0100 | (self.s & 0xFF);
+        }
+        bus.read(self.s as u32)
+    }
+
+    fn push_sized<B: Bus>(&mut self, bus: &mut B, value: u16, eight: bool) {
+        if !eight {
+            self.push(bus, (value >> 8) as u8);
+        }
+        self.push(bus, value as u8);
+    }
+
+    fn pull_sized<B: Bus>(&mut self, bus: &mut B, eight: bool) -> u16 {
+        let lo = self.pull(bus) as u16;
+        if eight {
+            lo
+        } else {
+            let hi = self.pull(bus) as u16;
+            (hi << 8) | lo
+        }
+    }
+
+    fn data_address(&self, address: u16) -> u32 {
+        ((self.db as u32) << 16) | address as u32
+    }
+
+    fn operand<B: Bus>(&mut self, bus: &mut B, mode: Mode) -> Operand {
+        use Mode::*;
+        let long_add = |base: u32, index: u16| (base + index as u32) & 0xFF_FFFF;
+        match mode {
+            Implied | Accumulator => Operand::None,
+            ImmediateM => {
+                let v = if self.m8() {
+                    self.fetch(bus) as u16
+                } else {
+                    self.fetch_u16(bus)
+                };
+                Operand::Value(v)
+            }
+            ImmediateX => {
+                let v = if self.x8() {
+                    self.fetch(bus) as u16
+                } else {
+                    self.fetch_u16(bus)
+                };
+                Operand::Value(v)
+            }
+            Immediate8 => Operand::Value(self.fetch(bus) as u16),
+            Immediate16 => Operand::Value(self.fetch_u16(bus)),
+            Relative8 => {
+                let offset = self.fetch(bus) as i8;
+                Operand::Value(self.pc.wrapping_add(offset as u16))
+            }
+            Relative16 => {
+                let offset = self.fetch_u16(bus);
+                Operand::Value(self.pc.wrapping_add(offset))
+            }
+            Direct => {
+                let op = self.fetch(bus) as u16;
+                Operand::Address(self.d.wrapping_add(op) as u32)
+            }
+            DirectX => {
+                let op = self.fetch(bus) as u16;
+                Operand::Address(self.d.wrapping_add(op).wrapping_add(self.x) as u32)
+            }
+            DirectY => {
+                let op = self.fetch(bus) as u16;
+                Operand::Address(self.d.wrapping_add(op).wrapping_add(self.y) as u32)
+            }
+            DirectIndirect => {
+                let op = self.fetch(bus) as u16;
+                let ptr = Cpu::read_u16(bus, self.d.wrapping_add(op) as u32);
+                Operand::Address(self.data_address(ptr))
+            }
+            DirectIndirectX => {
+                let op = self.fetch(bus) as u16;
+                let ptr = Cpu::read_u16(bus, self.d.wrapping_add(op).wrapping_add(self.x) as u32);
+                Operand::Address(self.data_address(ptr))
+            }
+            DirectIndirectY => {
+                let op = self.fetch(bus) as u16;
+                let ptr = Cpu::read_u16(bus, self.d.wrapping_add(op) as u32);
+                Operand::Address(long_add(self.data_address(ptr), self.y))
+            }
+            DirectIndirectLong => {
+                let op = self.fetch(bus) as u16;
+                Operand::Address(Cpu::read_u24(bus, self.d.wrapping_add(op) as u32))
+            }
+            DirectIndirectLongY => {
+                let op = self.fetch(bus) as u16;
+                let ptr = Cpu::read_u24(bus, self.d.wrapping_add(op) as u32);
+                Operand::Address(long_add(ptr, self.y))
+            }
+            Absolute => {
+                let op = self.fetch_u16(bus);
+                Operand::Address(self.data_address(op))
+            }
+            AbsoluteX => {
+                let op = self.fetch_u16(bus);
+                Operand::Address(long_add(self.data_address(op), self.x))
+            }
+            AbsoluteY => {
+                let op = self.fetch_u16(bus);
+                Operand::Address(long_add(self.data_address(op), self.y))
+            }
+            AbsoluteLong => Operand::Address(self.fetch_u24(bus)),
+            AbsoluteLongX => {
+                let op = self.fetch_u24(bus);
+                Operand::Address(long_add(op, self.x))
+            }
+            // The indirect jump modes are resolved by the jump instructions
+            // themselves since they depend on the program bank.
+            AbsoluteIndirect | AbsoluteIndirectX | AbsoluteIndirectLong => {
+                Operand::Value(self.fetch_u16(bus))
+            }
+            StackRelative => {
+                let op = self.fetch(bus) as u16;
+                Operand::Address(self.s.wrapping_add(op) as u32)
+            }
+            StackRelativeIndirectY => {
+                let op = self.fetch(bus) as u16;
+                let ptr = Cpu::read_u16(bus, self.s.wrapping_add(op) as u32);
+                Operand::Address(long_add(self.data_address(ptr), self.y))
+            }
+            BlockMove => Operand::Value(self.fetch_u16(bus)),
+        }
+    }
+
+    fn load<B: Bus>(bus: &mut B, operand: Operand, eight: bool) -> u16 {
+        match operand {
+            Operand::Value(v) => v,
+            Operand::Address(address) => Cpu::read_sized(bus, address, eight),
+            Operand::None => unreachable!("load without an operand"),
+        }
+    }
+
+    fn address(operand: Operand) -> u32 {
+        match operand {
+            Operand::Address(address) => address,
+            _ => unreachable!("expected an address operand"),
+        }
+    }
+
+    fn set_a(&mut self, value: u16) {
+        if self.m8() {
+            self.a = (self.a & 0xFF00) | (value & 0xFF);
+        } else {
+            self.a = value;
+        }
+    }
+
+    fn adc(&mut self, value: u16) {
+        let eight = self.m8();
+        let carry = self.flag(FLAG_C) as u32;
+        let (a, value, mask, sign) = if eight {
+            (self.a as u32 & 0xFF, value as u32 & 0xFF, 0xFFu32, 0x80u32)
+        } else {
+            (self.a as u32, value as u32, 0xFFFFu32, 0x8000u32)
+        };
+        let result = if self.flag(FLAG_D) {
+            // Add one BCD digit at a time so we get the same carry behaviour
+            // as the hardware for valid BCD inputs.
+            let digits = if eight { 2 } else { 4 };
+            let mut result = 0u32;
+            let mut c = carry;
+            for i in 0..digits {
+                let shift = i * 4;
+                let mut digit = ((a >> shift) & 0xF) + ((value >> shift) & 0xF) + c;
+                c = 0;
+                if digit > 9 {
+                    digit += 6;
+                    c = 1;
+                }
+                result |= (digit & 0xF) << shift;
+            }
+            result | (c << (digits * 4))
+        } else {
+            a + value + carry
+        };
+        self.set_flag(FLAG_C, result > mask);
+        self.set_flag(FLAG_V, (!(a ^ value) & (a ^ result) & sign) != 0);
+        self.set_a((result & mask) as u16);
+        self.set_nz((result & mask) as u16, eight);
+    }
+
+    fn sbc(&mut self, value: u16) {
+        let eight = self.m8();
+        if self.flag(FLAG_D) {
+            // Subtract one BCD digit at a time, borrowing as we go
+            let digits = if eight { 2 } else { 4 };
+            let (a, value, mask, sign) = if eight {
+                (self.a as i32 & 0xFF, value as i32 & 0xFF, 0xFFi32, 0x80i32)
+            } else {
+                (self.a as i32, value as i32, 0xFFFFi32, 0x8000i32)
+            };
+            let mut borrow = 1 - self.flag(FLAG_C) as i32;
+            let mut result = 0i32;
+            for i in 0..digits {
+                let shift = i * 4;
+                let mut digit = ((a >> shift) & 0xF) - ((value >> shift) & 0xF) - borrow;
+                borrow = 0;
+                if digit < 0 {
+                    digit += 10;
+                    borrow = 1;
+                }
+                result |= (digit & 0xF) << shift;
+            }
+            let binary = a - value - (1 - self.flag(FLAG_C) as i32);
+            self.set_flag(FLAG_C, borrow == 0);
+            self.set_flag(FLAG_V, ((a ^ value) & (a ^ binary) & sign) != 0);
+            self.set_a((result & mask) as u16);
+            self.set_nz((result & mask) as u16, eight);
+        } else {
+            let inverted = if eight { !value & 0xFF } else { !value };
+            self.adc(inverted);
+        }
+    }
+
+    fn compare(&mut self, register: u16, value: u16, eight: bool) {
+        let (register, value) = if eight {
+            (register & 0xFF, value & 0xFF)
+        } else {
+            (register, value)
+        };
+        self.set_flag(FLAG_C, register >= value);
+        self.set_nz(register.wrapping_sub(value), eight);
+    }
+
+    // Read-modify-write helper for the shift/rotate/inc/dec family
+    fn modify<B: Bus>(&mut self, bus: &mut B, operand: Operand, f: impl Fn(&mut Cpu, u16) -> u16) {
+        let eight = self.m8();
+        match operand {
+            Operand::None => {
+                let value = if eight { self.a & 0xFF } else { self.a };
+                let result = f(self, value);
+                self.set_a(result);
+                self.set_nz(result, eight);
+            }
+            Operand::Address(address) => {
+                let value = Cpu::read_sized(bus, address, eight);
+                let result = f(self, value);
+                Cpu::write_sized(bus, address, result, eight);
+                self.set_nz(result, eight);
+            }
+            Operand::Value(_) => unreachable!("cannot modify an immediate"),
+        }
+    }
+
+    fn branch(&mut self, target: Operand, taken: bool) {
+        if taken {
+            if let Operand::Value(pc) = target {
+                self.pc = pc;
+            }
+        }
+    }
+
+    // Execute one instruction.
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> Result<(), Box<dyn Error>> {
+        let at = self.pc_long();
+        let opcode = self.fetch(bus);
+        let (op, mode) = decode(opcode);
+        let operand = self.operand(bus, mode);
+        let m8 = self.m8();
+        let x8 = self.x8();
+        let high_bit = |eight: bool| if eight { 0x80u16 } else { 0x8000u16 };
+        let mask = |eight: bool| if eight { 0xFFu16 } else { 0xFFFFu16 };
+        use Op::*;
+        match op {
+            Lda => {
+                let v = Cpu::load(bus, operand, m8);
+                self.set_a(v);
+                self.set_nz(v, m8);
+            }
+            Ldx => {
+                self.x = Cpu::load(bus, operand, x8);
+                self.set_nz(self.x, x8);
+            }
+            Ldy => {
+                self.y = Cpu::load(bus, operand, x8);
+                self.set_nz(self.y, x8);
+            }
+            Sta => Cpu::write_sized(bus, Cpu::address(operand), self.a, m8),
+            Stx => Cpu::write_sized(bus, Cpu::address(operand), self.x, x8),
+            Sty => Cpu::write_sized(bus, Cpu::address(operand), self.y, x8),
+            Stz => Cpu::write_sized(bus, Cpu::address(operand), 0, m8),
+            Adc => {
+                let v = Cpu::load(bus, operand, m8);
+                self.adc(v);
+            }
+            Sbc => {
+                let v = Cpu::load(bus, operand, m8);
+                self.sbc(v);
+            }
+            And | Ora | Eor => {
+                let v = Cpu::load(bus, operand, m8);
+                let a = match op {
+                    And => self.a & v,
+                    Ora => self.a | v,
+                    _ => self.a ^ v,
+                };
+                self.set_a(a);
+                self.set_nz(a, m8);
+            }
+            Cmp => {
+                let v = Cpu::load(bus, operand, m8);
+                self.compare(self.a, v, m8);
+            }
+            Cpx => {
+                let v = Cpu::load(bus, operand, x8);
+                self.compare(self.x, v, x8);
+            }
+            Cpy => {
+                let v = Cpu::load(bus, operand, x8);
+                self.compare(self.y, v, x8);
+            }
+            Bit => {
+                let v = Cpu::load(bus, operand, m8) & mask(m8);
+                self.set_flag(FLAG_Z, self.a & v == 0);
+                if mode != Mode::ImmediateM {
+                    self.set_flag(FLAG_N, v & high_bit(m8) != 0);
+                    self.set_flag(FLAG_V, v & (high_bit(m8) >> 1) != 0);
+                }
+            }
+            Tsb | Trb => {
+                let address = Cpu::address(operand);
+                let v = Cpu::read_sized(bus, address, m8);
+                let a = self.a & mask(m8);
+                self.set_flag(FLAG_Z, v & a == 0);
+                let result = if op == Tsb { v | a } else { v & !a };
+                Cpu::write_sized(bus, address, result, m8);
+            }
+            Inc => self.modify(bus, operand, |_, v| v.wrapping_add(1) & mask(m8)),
+            Dec => self.modify(bus, operand, |_, v| v.wrapping_sub(1) & mask(m8)),
+            Asl => self.modify(bus, operand, |cpu, v| {
+                cpu.set_flag(FLAG_C, v & high_bit(m8) != 0);
+                (v << 1) & mask(m8)
+            }),
+            Lsr => self.modify(bus, operand, |cpu, v| {
+                cpu.set_flag(FLAG_C, v & 1 != 0);
+                v >> 1
+            }),
+            Rol => self.modify(bus, operand, |cpu, v| {
+                let c = cpu.flag(FLAG_C) as u16;
+                cpu.set_flag(FLAG_C, v & high_bit(m8) != 0);
+                ((v << 1) | c) & mask(m8)
+            }),
+            Ror => self.modify(bus, operand, |cpu, v| {
+                let c = if cpu.flag(FLAG_C) { high_bit(m8) } else { 0 };
+                cpu.set_flag(FLAG_C, v & 1 != 0);
+                (v >> 1) | c
+            }),
+            Inx => {
+                self.x = self.x.wrapping_add(1) & mask(x8);
+                self.set_nz(self.x, x8);
+            }
+            Iny => {
+                self.y = self.y.wrapping_add(1) & mask(x8);
+                self.set_nz(self.y, x8);
+            }
+            Dex => {
+                self.x = self.x.wrapping_sub(1) & mask(x8);
+                self.set_nz(self.x, x8);
+            }
+            Dey => {
+                self.y = self.y.wrapping_sub(1) & mask(x8);
+                self.set_nz(self.y, x8);
+            }
+            Bra | Brl => self.branch(operand, true),
+            Bpl => self.branch(operand, !self.flag(FLAG_N)),
+            Bmi => self.branch(operand, self.flag(FLAG_N)),
+            Bvc => self.branch(operand, !self.flag(FLAG_V)),
+            Bvs => self.branch(operand, self.flag(FLAG_V)),
+            Bcc => self.branch(operand, !self.flag(FLAG_C)),
+            Bcs => self.branch(operand, self.flag(FLAG_C)),
+            Bne => self.branch(operand, !self.flag(FLAG_Z)),
+            Beq => self.branch(operand, self.flag(FLAG_Z)),
+            Jmp | Jsr => {
+                let target = match (mode, operand) {
+                    (Mode::AbsoluteIndirect, Operand::Value(ptr)) => Cpu::read_u16(bus, ptr as u32),
+                    (Mode::AbsoluteIndirectX, Operand::Value(ptr)) => {
+                        let ptr = ptr.wrapping_add(self.x);
+                        Cpu::read_u16(bus, ((self.pb as u32) << 16) | ptr as u32)
+                    }
+                    // Absolute resolves as a data address, only the low 16
+                    // bits matter since jumps stay in the program bank
+                    _ => Cpu::address(operand) as u16,
+                };
+                if op == Jsr {
+                    let ret = self.pc.wrapping_sub(1);
+                    self.push_sized(bus, ret, false);
+                }
+                self.pc = target;
+            }
+            Jml | Jsl => {
+                let target = match (mode, operand) {
+                    (Mode::AbsoluteIndirectLong, Operand::Value(ptr)) => {
+                        Cpu::read_u24(bus, ptr as u32)
+                    }
+                    _ => Cpu::address(operand),
+                };
+                if op == Jsl {
+                    self.push(bus, self.pb);
+                    let ret = self.pc.wrapping_sub(1);
+                    self.push_sized(bus, ret, false);
+                }
+                self.pb = (target >> 16) as u8;
+                self.pc = target as u16;
+            }
+            Rts => {
+                self.pc = self.pull_sized(bus, false).wrapping_add(1);
+            }
+            Rtl => {
+                self.pc = self.pull_sized(bus, false).wrapping_add(1);
+                self.pb = self.pull(bus);
+            }
+            Pha => self.push_sized(bus, self.a, m8),
+            Phx => self.push_sized(bus, self.x, x8),
+            Phy => self.push_sized(bus, self.y, x8),
+            Phb => self.push(bus, self.db),
+            Phk => self.push(bus, self.pb),
+            Phd => self.push_sized(bus, self.d, false),
+            Php => self.push(bus, self.p),
+            Pea => {
+                if let Operand::Value(v) = operand {
+                    self.push_sized(bus, v, false);
+                }
+            }
+            Pei => {
+                // The operand already went through the direct page pointer,
+                // its low 16 bits are the pointer itself
+                let ptr = Cpu::address(operand) as u16;
+                self.push_sized(bus, ptr, false);
+            }
+            Per => {
+                if let Operand::Value(v) = operand {
+                    self.push_sized(bus, v, false);
+                }
+            }
+            Pla => {
+                let v = self.pull_sized(bus, m8);
+                self.set_a(v);
+                self.set_nz(v, m8);
+            }
+            Plx => {
+                self.x = self.pull_sized(bus, x8);
+                self.set_nz(self.x, x8);
+            }
+            Ply => {
+                self.y = self.pull_sized(bus, x8);
+                self.set_nz(self.y, x8);
+            }
+            Plb => {
+                self.db = self.pull(bus);
+                self.set_nz(self.db as u16, true);
+            }
+            Pld => {
+                self.d = self.pull_sized(bus, false);
+                self.set_nz(self.d, false);
+            }
+            Plp => {
+                let p = self.pull(bus);
+                self.set_p(p);
+            }
+            Rep => {
+                if let Operand::Value(v) = operand {
+                    self.set_p(self.p & !(v as u8));
+                }
+            }
+            Sep => {
+                if let Operand::Value(v) = operand {
+                    self.set_p(self.p | v as u8);
+                }
+            }
+            Clc => self.set_flag(FLAG_C, false),
+            Sec => self.set_flag(FLAG_C, true),
+            Cld => self.set_flag(FLAG_D, false),
+            Sed => self.set_flag(FLAG_D, true),
+            Cli => self.set_flag(FLAG_I, false),
+            Sei => self.set_flag(FLAG_I, true),
+            Clv => self.set_flag(FLAG_V, false),
+            Tax => {
+                self.x = self.a & mask(x8);
+                self.set_nz(self.x, x8);
+            }
+            Tay => {
+                self.y = self.a & mask(x8);
+                self.set_nz(self.y, x8);
+            }
+            Txa => {
+                self.set_a(self.x);
+                self.set_nz(self.x, m8);
+            }
+            Tya => {
+                self.set_a(self.y);
+                self.set_nz(self.y, m8);
+            }
+            Txy => {
+                self.y = self.x;
+                self.set_nz(self.y, x8);
+            }
+            Tyx => {
+                self.x = self.y;
+                self.set_nz(self.x, x8);
+            }
+            Tsx => {
+                self.x = self.s & mask(x8);
+                self.set_nz(self.x, x8);
+            }
+            Txs => {
+                self.s = if self.e {
+                    0x0100 | (self.x & 0xFF)
+                } else {
+                    self.x
+                }
+            }
+            Tcd => {
+                self.d = self.a;
+                self.set_nz(self.d, false);
+            }
+            Tdc => {
+                self.a = self.d;
+                self.set_nz(self.a, false);
+            }
+            Tcs => {
+                self.s = if self.e {
+                    0x0100 | (self.a & 0xFF)
+                } else {
+                    self.a
+                }
+            }
+            Tsc => {
+                self.a = self.s;
+                self.set_nz(self.a, false);
+            }
+            Xba => {
+                self.a = self.a.swap_bytes();
+                self.set_nz(self.a, true);
+            }
+            Xce => {
+                let c = self.flag(FLAG_C);
+                self.set_flag(FLAG_C, self.e);
+                self.e = c;
+                if self.e {
+                    self.s = 0x0100 | (self.s & 0xFF);
+                }
+                self.set_p(self.p);
+            }
+            Mvn | Mvp => {
+                if let Operand::Value(banks) = operand {
+                    let dst_bank = banks as u8;
+                    let src_bank = (banks >> 8) as u8;
+                    self.db = dst_bank;
+                    let src = ((src_bank as u32) << 16) | self.x as u32;
+                    let dst = ((dst_bank as u32) << 16) | self.y as u32;
+                    let data = bus.read(src);
+                    bus.write(dst, data);
+                    if op == Mvn {
+                        self.x = self.x.wrapping_add(1) & mask(x8);
+                        self.y = self.y.wrapping_add(1) & mask(x8);
+                    } else {
+                        self.x = self.x.wrapping_sub(1) & mask(x8);
+                        self.y = self.y.wrapping_sub(1) & mask(x8);
+                    }
+                    self.a = self.a.wrapping_sub(1);
+                    if self.a != 0xFFFF {
+                        // Repeat the instruction until the count runs out
+                        self.pc = self.pc.wrapping_sub(3);
+                    }
+                }
+            }
+            Nop | Wdm => {}
+            Brk | Cop | Rti | Stp | Wai => {
+                return Err(format!("unsupported instruction {:?} at {:06x}", op, at).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+// True when the next instruction is the `jmp ($ffea)` that hands control back
+// to the game's NMI handler.
+pub fn at_nmi_exit<B: Bus>(cpu: &Cpu, bus: &mut B) -> bool {
+    let pc = cpu.pc_long();
+    bus.read(pc) == 0x6c && bus.read(pc + 1) == 0xea && bus.read(pc + 2) == 0xff
+}
+
+// Load `payload` into the CMD space and run it the way the NMI hook would,
+// stopping right before the final `jmp ($ffea)`.
+pub fn run_nmi_payload<B: Bus>(bus: &mut B, payload: &[u8]) -> Result<Cpu, Box<dyn Error>> {
+    for (i, b) in payload.iter().enumerate() {
+        bus.write(CMD_ADDR + i as u32, *b);
+    }
+    let mut cpu = Cpu::nmi_entry();
+    for _ in 0..MAX_STEPS {
+        if at_nmi_exit(&cpu, bus) {
+            return Ok(cpu);
+        }
+        cpu.step(bus)?;
+    }
+    Err(format!(
+        "payload did not reach jmp ($ffea) after {} steps",
+        MAX_STEPS
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    const REP_M: [u8; 2] = [0xc2, 0x20];
+    const REP_X: [u8; 2] = [0xc2, 0x10];
+
+    // Run `code` from the NMI entry state and return the CPU where it stopped
+    fn run(bus: &mut MemoryBus, code: &[&[u8]]) -> Cpu {
+        let mut program = code.concat();
+        // jmp ($ffea)
+        program.extend_from_slice(&[0x6c, 0xea, 0xff]);
+        run_nmi_payload(bus, &program).unwrap()
+    }
+
+    #[test]
+    fn operand_width_follows_m_flag() {
+        let mut bus = MemoryBus::new();
+        let cpu = run(
+            &mut bus,
+            &[
+                &lda_immediate_u8(0x12),
+                &sta_long(0x7E_0100),
+                &REP_M,
+                &lda_immediate_u16(0x3456),
+                &sta_long(0x7E_0102),
+            ],
+        );
+        assert_eq!(bus.read_u16(0x7E_0100), 0x0012);
+        assert_eq!(bus.read_u16(0x7E_0102), 0x3456);
+        assert!(!cpu.m8());
+        assert!(cpu.x8());
+    }
+
+    #[test]
+    fn index_width_follows_x_flag() {
+        let mut bus = MemoryBus::new();
+        let cpu = run(
+            &mut bus,
+            &[
+                // ldx #$FF; inx
+                &[0xa2, 0xff, 0xe8],
+                &REP_X,
+                // ldy #$1234
+                &[0xa0, 0x34, 0x12],
+            ],
+        );
+        // 8-bit X wraps
+        assert_eq!(cpu.x, 0);
+        assert_eq!(cpu.y, 0x1234);
+    }
+
+    #[test]
+    fn decimal_mode_add_and_subtract() {
+        let mut bus = MemoryBus::new();
+        run(
+            &mut bus,
+            &[
+                &sed(),
+                &clc(),
+                &lda_immediate_u8(0x19),
+                &adc_immediate_u8(0x03),
+                &sta_long(0x7E_0100),
+                &sec(),
+                &lda_immediate_u8(0x10),
+                &sbc_immediate_u8(0x01),
+                &sta_long(0x7E_0101),
+                &clc(),
+                &lda_immediate_u8(0x95),
+                &adc_immediate_u8(0x10),
+                &sta_long(0x7E_0102),
+                &cld(),
+            ],
+        );
+        assert_eq!(bus.read(0x7E_0100), 0x22);
+        assert_eq!(bus.read(0x7E_0101), 0x09);
+        // 95 + 10 carries out and leaves 05
+        assert_eq!(bus.read(0x7E_0102), 0x05);
+    }
+
+    #[test]
+    fn branches() {
+        let mut bus = MemoryBus::new();
+        run(
+            &mut bus,
+            &[
+                &lda_immediate_u8(0x00),
+                &beq(2),
+                &lda_immediate_u8(0x01),
+                &sta_long(0x7E_0100),
+                &lda_immediate_u8(0x05),
+                &cmp_immediate_u8(0x06),
+                &bcs(2),
+                &lda_immediate_u8(0x07),
+                &sta_long(0x7E_0101),
+            ],
+        );
+        assert_eq!(bus.read(0x7E_0100), 0x00);
+        assert_eq!(bus.read(0x7E_0101), 0x07);
+    }
+
+    #[test]
+    fn stack_round_trip() {
+        let mut bus = MemoryBus::new();
+        let cpu = run(
+            &mut bus,
+            &[
+                &REP_M,
+                &lda_immediate_u16(0xBEEF),
+                // pha
+                &[0x48],
+                &lda_immediate_u16(0),
+                // pla
+                &[0x68],
+            ],
+        );
+        assert_eq!(cpu.a, 0xBEEF);
+        assert_eq!(cpu.s, Cpu::nmi_entry().s);
+    }
+
+    #[test]
+    fn absolute_addressing_uses_the_data_bank() {
+        let mut bus = MemoryBus::new();
+        run(
+            &mut bus,
+            &[
+                &lda_immediate_u8(0x01),
+                &sta_absolute(0x09C2),
+                &sta_absolute(0xD820),
+            ],
+        );
+        // Low WRAM is mirrored into bank $80...
+        assert_eq!(bus.read(0x7E_09C2), 0x01);
+        // ...but $D820 in bank $80 is ROM, not WRAM
+        assert_eq!(bus.read(0x7E_D820), 0x00);
+        assert_eq!(bus.read(0x80_D820), 0x01);
+    }
+
+    #[test]
+    fn runaway_loop_is_an_error() {
+        let mut bus = MemoryBus::new();
+        assert!(run_nmi_payload(&mut bus, &bra(-2)).is_err());
+    }
+}
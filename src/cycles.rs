@@ -0,0 +1,249 @@
+//! Rough cycle estimates for the straight line code we run from the NMI hook.
+//!
+//! Every instruction is counted once (branches are assumed not taken) and
+//! memory speed is derived from the address each access goes to, so the
+//! numbers are an upper-ish bound for our payloads rather than exact timing.
+
+use crate::cpu::{decode, Mode, Op, CMD_ADDR, FLAG_M, FLAG_X};
+
+// Master clock cycles per CPU cycle for each kind of access
+pub const FAST: u32 = 6;
+pub const SLOW: u32 = 8;
+pub const XSLOW: u32 = 12;
+
+// One scanline is 1364 master cycles on NTSC
+pub const MASTER_CYCLES_PER_SCANLINE: u32 = 1364;
+// The game's own NMI handler already spends most of vblank on DMA, so by
+// default only give our payloads a handful of scanlines worth of time.
+pub const DEFAULT_BUDGET: u32 = 10 * MASTER_CYCLES_PER_SCANLINE;
+
+// The data bank the game runs with, used to guess where `abs` operands go
+const ASSUMED_DATA_BANK: u32 = 0x80;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CycleEstimate {
+    pub cycles: u32,
+    pub master_cycles: u32,
+}
+
+impl CycleEstimate {
+    pub fn scanlines(&self) -> f32 {
+        self.master_cycles as f32 / MASTER_CYCLES_PER_SCANLINE as f32
+    }
+}
+
+impl std::ops::Add for CycleEstimate {
+    type Output = CycleEstimate;
+
+    fn add(self, other: CycleEstimate) -> CycleEstimate {
+        CycleEstimate {
+            cycles: self.cycles + other.cycles,
+            master_cycles: self.master_cycles + other.master_cycles,
+        }
+    }
+}
+
+impl std::ops::AddAssign for CycleEstimate {
+    fn add_assign(&mut self, other: CycleEstimate) {
+        *self = *self + other;
+    }
+}
+
+// Memory speed for a 24-bit address, FastROM is assumed to be enabled.
+pub fn access_speed(address: u32) -> u32 {
+    let bank = (address >> 16) & 0xFF;
+    let offset = address & 0xFFFF;
+    let system_bank = matches!(bank, 0x00..=0x3F | 0x80..=0xBF);
+    if system_bank && offset < 0x8000 {
+        return match offset {
+            0x0000..=0x1FFF => SLOW,
+            0x2000..=0x3FFF => FAST,
+            0x4000..=0x41FF => XSLOW,
+            0x4200..=0x5FFF => FAST,
+            _ => SLOW,
+        };
+    }
+    if bank >= 0x80 {
+        FAST
+    } else {
+        SLOW
+    }
+}
+
+// Base cycle count for an instruction with 8-bit registers, and how many
+// data bytes it touches.
+fn base_cycles(op: Op, mode: Mode) -> (u32, u32) {
+    use Mode::*;
+    use Op::*;
+    let rmw = matches!(op, Asl | Lsr | Rol | Ror | Inc | Dec | Tsb | Trb);
+    match (op, mode) {
+        (_, Accumulator) => (2, 0),
+        (Rep | Sep, _) => (3, 0),
+        (Php | Pha | Phx | Phy | Phb | Phk, _) => (3, 0),
+        (Phd, _) => (4, 0),
+        (Pla | Plx | Ply | Plb | Plp, _) => (4, 0),
+        (Pld, _) => (5, 0),
+        (Xba, _) => (3, 0),
+        (Pea, _) => (5, 0),
+        (Pei, _) => (6, 0),
+        (Per, _) => (6, 0),
+        (Bra, _) => (3, 0),
+        (Brl, _) => (4, 0),
+        (Bcc | Bcs | Beq | Bne | Bmi | Bpl | Bvc | Bvs, _) => (2, 0),
+        (Jmp, Absolute) => (3, 0),
+        (Jmp, AbsoluteIndirect) => (5, 0),
+        (Jmp, AbsoluteIndirectX) => (6, 0),
+        (Jml, AbsoluteLong) => (4, 0),
+        (Jml, _) => (6, 0),
+        (Jsr, Absolute) => (6, 0),
+        (Jsr, _) => (8, 0),
+        (Jsl, _) => (8, 0),
+        (Rts | Rtl, _) => (6, 0),
+        (Rti, _) => (7, 0),
+        (Brk | Cop, _) => (8, 0),
+        (Mvn | Mvp, _) => (7, 1),
+        (Wai | Stp, _) => (3, 0),
+        (_, Implied) => (2, 0),
+        (_, Immediate8 | Immediate16 | ImmediateM | ImmediateX) => (2, 0),
+        (_, Direct) if rmw => (5, 2),
+        (_, DirectX) if rmw => (6, 2),
+        (_, Absolute) if rmw => (6, 2),
+        (_, AbsoluteX) if rmw => (7, 2),
+        (_, Direct) => (3, 1),
+        (_, DirectX | DirectY | StackRelative) => (4, 1),
+        (_, DirectIndirect | DirectIndirectY) => (5, 1),
+        (_, DirectIndirectX | DirectIndirectLong | DirectIndirectLongY) => (6, 1),
+        (_, Absolute | AbsoluteX | AbsoluteY) => (4, 1),
+        (_, AbsoluteLong | AbsoluteLongX) => (5, 1),
+        (_, StackRelativeIndirectY) => (7, 1),
+        (_, Relative8 | Relative16 | AbsoluteIndirect | AbsoluteIndirectX) => (2, 0),
+        (_, AbsoluteIndirectLong | BlockMove) => (2, 0),
+    }
+}
+
+// Extra cycles and data bytes for 16-bit registers
+fn wide_penalty(op: Op, m8: bool, x8: bool) -> u32 {
+    use Op::*;
+    let rmw = matches!(op, Asl | Lsr | Rol | Ror | Inc | Dec | Tsb | Trb);
+    let uses_x = matches!(
+        op,
+        Ldx | Ldy | Stx | Sty | Cpx | Cpy | Phx | Phy | Plx | Ply
+    );
+    let uses_m = matches!(
+        op,
+        Lda | Sta | Stz | Adc | Sbc | And | Ora | Eor | Cmp | Bit | Pha | Pla
+    ) || rmw;
+    if uses_x && !x8 {
+        1
+    } else if uses_m && !m8 && rmw {
+        2
+    } else if uses_m && !m8 {
+        1
+    } else {
+        0
+    }
+}
+
+// Best effort guess at which address an instruction's data accesses go to
+fn data_address(mode: Mode, operand: u32) -> Option<u32> {
+    use Mode::*;
+    match mode {
+        Direct | DirectX | DirectY | StackRelative => Some(operand & 0xFF),
+        Absolute | AbsoluteX | AbsoluteY => Some((ASSUMED_DATA_BANK << 16) | (operand & 0xFFFF)),
+        AbsoluteLong | AbsoluteLongX => Some(operand),
+        _ => None,
+    }
+}
+
+// Estimate the cost of `code` as if it was loaded at the CMD space and
+// started with the given register widths.
+pub fn estimate(code: &[u8], m8: bool, x8: bool) -> CycleEstimate {
+    let mut total = CycleEstimate::default();
+    let mut m8 = m8;
+    let mut x8 = x8;
+    let mut pc = 0usize;
+    while pc < code.len() {
+        let (op, mode) = decode(code[pc]);
+        let len = 1 + mode.operand_len(m8, x8);
+        let mut operand = 0u32;
+        for (i, b) in code.iter().skip(pc + 1).take(len - 1).enumerate() {
+            operand |= (*b as u32) << (8 * i);
+        }
+
+        let (base, data_bytes) = base_cycles(op, mode);
+        let penalty = wide_penalty(op, m8, x8);
+        let cycles = base + penalty;
+        let data_bytes = if data_bytes > 0 {
+            data_bytes + penalty
+        } else {
+            0
+        };
+        let fetch_speed = access_speed(CMD_ADDR + pc as u32);
+        let data_speed = data_address(mode, operand)
+            .map(access_speed)
+            .unwrap_or(SLOW);
+        // Whatever isn't an instruction fetch or a data access is either a
+        // stack access or an internal operation, count those as slow.
+        let fetches = len as u32;
+        let other = cycles.saturating_sub(fetches + data_bytes);
+        total += CycleEstimate {
+            cycles,
+            master_cycles: fetches * fetch_speed + data_bytes * data_speed + other * SLOW,
+        };
+
+        match (op, operand as u8) {
+            (Op::Rep, flags) => {
+                m8 &= flags & FLAG_M == 0;
+                x8 &= flags & FLAG_X == 0;
+            }
+            (Op::Sep, flags) => {
+                m8 |= flags & FLAG_M != 0;
+                x8 |= flags & FLAG_X != 0;
+            }
+            _ => {}
+        }
+        // An indirect or long jump hands control back to the game (the
+        // final `jmp ($ffea)`), nothing after it runs as part of this NMI.
+        if matches!(op, Op::Jmp | Op::Jml) && mode != Mode::Absolute {
+            break;
+        }
+        pc += len;
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn estimate_matches_a_hand_count() {
+        // Code is fetched from the CMD space at $2C00, which is fast
+        let mut block = vec![];
+        // 3 cycles: 3 fetches
+        block.extend_from_slice(&lda_immediate_u16(0x1234));
+        // 5 cycles: 3 fetches, 2 slow WRAM writes
+        block.extend_from_slice(&sta_absolute(0x09C2));
+        // 6 cycles: 4 fetches, 2 slow WRAM writes
+        block.extend_from_slice(&sta_long(0x7E_1000));
+        // 3 cycles: 2 fetches, 1 internal
+        block.push(0xe2); // sep #$20
+        block.push(0x20);
+        // 2 cycles: 2 fetches
+        block.extend_from_slice(&lda_immediate_u8(0x05));
+        // 4 cycles: 3 fetches, 1 fast PPU write
+        block.extend_from_slice(&sta_absolute(0x2100));
+        // 3 cycles: 2 fetches, 1 internal
+        block.push(0xc2); // rep #$20
+        block.push(0x20);
+
+        let fetches = 3 + 3 + 4 + 2 + 2 + 3 + 2;
+        let expected = CycleEstimate {
+            cycles: 3 + 5 + 6 + 3 + 2 + 4 + 3,
+            master_cycles: fetches * FAST + 4 * SLOW + FAST + 2 * SLOW,
+        };
+        assert_eq!(estimate(&block, false, false), expected);
+        assert_eq!(expected.master_cycles, 168);
+    }
+}
@@ -0,0 +1,251 @@
+//! Only write the parts of Samus that changed.
+//!
+//! `samus_overwrite_asm()` writes every field, which puts back anything that
+//! moved on since the snapshot was taken (most noticeably her position). A
+//! `SamusDiff` compares the snapshot with the edited copy and only emits
+//! writes for the fields that differ.
+
+use crate::*;
+
+// A changed bitfield: its new value and which bits that turned on and off
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BitChange<T> {
+    pub value: T,
+    pub set: T,
+    pub clear: T,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SamusDiff {
+    words: BTreeMap<SamusField, u16>,
+    bits: BTreeMap<SamusField, BitChange<u16>>,
+    bosses: BTreeMap<Area, BitChange<u8>>,
+}
+
+fn item_bits(items: &HashSet<Item>) -> u16 {
+    items_to_u16(&items.iter().collect::<Vec<_>>())
+}
+
+fn beam_bits(beams: &HashSet<Beam>) -> u16 {
+    beams_to_u16(&beams.iter().collect::<Vec<_>>())
+}
+
+fn event_bits(events: &HashSet<Event>) -> u16 {
+    events_to_u16(&events.iter().collect::<Vec<_>>())
+}
+
+fn boss_bits(bosses: Option<&HashSet<Boss>>) -> u8 {
+    bosses
+        .map(|b| bosses_to_u8(&b.iter().collect::<Vec<_>>()))
+        .unwrap_or(0)
+}
+
+impl SamusDiff {
+    pub fn new(before: &Samus, after: &Samus) -> SamusDiff {
+        let mut diff = SamusDiff::default();
+        let words = [
+            (SamusField::HP, before.hp, after.hp),
+            (SamusField::MaxHP, before.max_hp, after.max_hp),
+            (SamusField::Missiles, before.missiles, after.missiles),
+            (
+                SamusField::MaxMissiles,
+                before.max_missiles,
+                after.max_missiles,
+            ),
+            (SamusField::Supers, before.supers, after.supers),
+            (SamusField::MaxSupers, before.max_supers, after.max_supers),
+            (SamusField::PBs, before.pbs, after.pbs),
+            (SamusField::MaxPBs, before.max_pbs, after.max_pbs),
+            (SamusField::ReserveHP, before.reserve_hp, after.reserve_hp),
+            (
+                SamusField::MaxReserveHP,
+                before.max_reserve_hp,
+                after.max_reserve_hp,
+            ),
+            (
+                SamusField::ReserveMode,
+                before.reserve_mode.into(),
+                after.reserve_mode.into(),
+            ),
+            (SamusField::XPosition, before.x_position, after.x_position),
+            (
+                SamusField::XSubPosition,
+                before.x_subposition,
+                after.x_subposition,
+            ),
+            (SamusField::YPosition, before.y_position, after.y_position),
+            (
+                SamusField::YSubPosition,
+                before.y_subposition,
+                after.y_subposition,
+            ),
+        ];
+        for (field, old, new) in words {
+            if old != new {
+                diff.words.insert(field, new);
+            }
+        }
+        let bits = [
+            (
+                SamusField::EquippedItems,
+                item_bits(&before.equipped_items),
+                item_bits(&after.equipped_items),
+            ),
+            (
+                SamusField::CollectedItems,
+                item_bits(&before.collected_items),
+                item_bits(&after.collected_items),
+            ),
+            (
+                SamusField::EquippedBeams,
+                beam_bits(&before.equipped_beams),
+                beam_bits(&after.equipped_beams),
+            ),
+            (
+                SamusField::CollectedBeams,
+                beam_bits(&before.collected_beams),
+                beam_bits(&after.collected_beams),
+            ),
+            (
+                SamusField::Events,
+                event_bits(&before.events),
+                event_bits(&after.events),
+            ),
+        ];
+        for (field, old, new) in bits {
+            if old != new {
+                diff.bits.insert(
+                    field,
+                    BitChange {
+                        value: new,
+                        set: new & !old,
+                        clear: old & !new,
+                    },
+                );
+            }
+        }
+        for area in before.bosses.keys().chain(after.bosses.keys()) {
+            let old = boss_bits(before.bosses.get(area));
+            let new = boss_bits(after.bosses.get(area));
+            if old != new {
+                diff.bosses.insert(
+                    *area,
+                    BitChange {
+                        value: new,
+                        set: new & !old,
+                        clear: old & !new,
+                    },
+                );
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty() && self.bits.is_empty() && self.bosses.is_empty()
+    }
+
+    // Writes for the changed fields. With `read_modify_write` bitfields only
+    // have the changed bits flipped, leaving whatever else the game set since
+    // the snapshot alone; otherwise the whole edited word is written.
+    pub fn to_asm(&self, read_modify_write: bool) -> Vec<u8> {
+        let mut r = Vec::new();
+        for (field, value) in &self.words {
+            r.extend_from_slice(&lda_immediate_u16(*value));
+            r.extend_from_slice(&sta_wram(*SAMUS_ADDR_MAP.get(field).unwrap()));
+        }
+        for (field, change) in &self.bits {
+            let address = *SAMUS_ADDR_MAP.get(field).unwrap();
+            if read_modify_write {
+                r.extend_from_slice(&lda_wram(address));
+                if change.set != 0 {
+                    r.extend_from_slice(&ora_immediate_u16(change.set));
+                }
+                if change.clear != 0 {
+                    r.extend_from_slice(&and_immediate_u16(!change.clear));
+                }
+            } else {
+                r.extend_from_slice(&lda_immediate_u16(change.value));
+            }
+            r.extend_from_slice(&sta_wram(address));
+        }
+
+        if !self.bosses.is_empty() {
+            // sep #$20
+            r.push(0xe2);
+            r.push(0x20);
+            for (area, change) in &self.bosses {
+                let address = 0x7E_0000
+                    + *SAMUS_ADDR_MAP.get(&SamusField::Bosses).unwrap() as u32
+                    + area_to_u8(area) as u32;
+                if read_modify_write {
+                    r.extend_from_slice(&lda_long(address));
+                    if change.set != 0 {
+                        r.extend_from_slice(&ora_immediate_u8(change.set));
+                    }
+                    if change.clear != 0 {
+                        r.extend_from_slice(&and_immediate_u8(!change.clear));
+                    }
+                } else {
+                    r.extend_from_slice(&lda_immediate_u8(change.value));
+                }
+                r.extend_from_slice(&sta_long(address));
+            }
+            // rep #$20
+            r.push(0xc2);
+            r.push(0x20);
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Bus, MemoryBus};
+
+    fn run(asm: &[u8]) -> MemoryBus {
+        let mut bus = MemoryBus::new();
+        // Something for the read-modify-write path to keep
+        bus.load(0x7E_D820, &[0x00, 0x80]);
+        let mut payload = Payload::new();
+        payload.push(asm);
+        payload.run_on(&mut bus);
+        bus
+    }
+
+    #[test]
+    fn writes_land_in_wram() {
+        let before = Samus::default();
+        let mut after = before.clone();
+        after.hp = 99;
+        after.events.insert(Event::ZebesAwake);
+        after
+            .bosses
+            .insert(Area::Brinstar, [Boss::MainBoss].into_iter().collect());
+        for read_modify_write in [true, false] {
+            let mut bus = run(&SamusDiff::new(&before, &after).to_asm(read_modify_write));
+            assert_eq!(bus.read_u16(0x7E_09C2), 99);
+            assert_eq!(bus.read(0x7E_D828 + area_to_u8(&Area::Brinstar) as u32), 1);
+            let events = bus.read_u16(0x7E_D820);
+            assert_eq!(events & 1, 1);
+            // Only the changed bit is touched when reading first
+            assert_eq!(events & 0x8000 != 0, read_modify_write);
+            // and nothing went to ROM
+            assert_eq!(bus.read_u16(0x80_D820), 0);
+        }
+    }
+
+    #[test]
+    fn clears_bits() {
+        let mut before = Samus::default();
+        before.events.insert(Event::ZebesAwake);
+        let after = Samus::default();
+        let mut bus = MemoryBus::new();
+        bus.load(0x7E_D820, &[0x01, 0x00]);
+        let mut payload = Payload::new();
+        payload.push(&SamusDiff::new(&before, &after).to_asm(true));
+        payload.run_on(&mut bus);
+        assert_eq!(bus.read_u16(0x7E_D820), 0);
+    }
+}
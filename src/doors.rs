@@ -0,0 +1,622 @@
+//! Which colored doors have been opened.
+//!
+//! Like items, every colored door PLM carries an index into a bitfield, this
+//! one at $7ED8B0. The game sets the bit once the door is shot open and skips
+//! spawning it after that. The index alone doesn't say where a door is, so
+//! the door table comes from the room data in the ROM: starting from the
+//! landing site and Ceres, follow every room's doors and note each door PLM
+//! with its color, facing, room and area. That way hacks get a table too.
+
+use crate::payload::{Payload, PayloadArgs};
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::collections::BTreeSet;
+use std::error::Error;
+
+pub const DOOR_BITS: u16 = 0xD8B0;
+pub const DOOR_BITS_LEN: usize = 0x40;
+
+// Room headers, their states and PLM populations are in bank $8F, the door
+// headers the rooms point at in bank $83
+const ROOM_BANK: u32 = 0x8F_8000;
+const DOOR_BANK: u32 = 0x83_8000;
+const BANK_SIZE: usize = 0x8000;
+// The landing site and the Ceres elevator, every other room is reachable
+// through doors from one of them
+const START_ROOMS: [u16; 2] = [0x91F8, 0xDF45];
+// Offsets into a room header
+const ROOM_AREA: u16 = 1;
+const ROOM_DOORS: u16 = 9;
+const ROOM_STATES: u16 = 11;
+// Offset of the PLM population pointer in a room state
+const STATE_PLMS: u16 = 20;
+// The state list ends with the default state, its data follows directly
+const STATE_DEFAULT: u16 = 0xE5E6;
+// Door lists aren't terminated, don't follow one further than this
+const MAX_ROOM_DOORS: u16 = 64;
+// Colored door PLMs go grey, yellow, green, red, each facing left, right,
+// up and down, 6 bytes apart
+const DOOR_PLMS: u16 = 0xC842;
+// A door PLM argument with this set has no bit, it closes again every time
+const NO_DOOR_BIT: u16 = 0x8000;
+// Grey doors keep what opens them in bits 10-14 of the argument
+const DOOR_INDEX_MASK: u16 = 0x03FF;
+
+#[derive(clap::ArgEnum, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DoorColor {
+    Grey,
+    Yellow,
+    Green,
+    Red,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Facing {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+fn door_plm(id: u16) -> Option<(DoorColor, Facing)> {
+    if id < DOOR_PLMS || !(id - DOOR_PLMS).is_multiple_of(6) {
+        return None;
+    }
+    let n = ((id - DOOR_PLMS) / 6) as usize;
+    let colors = [
+        DoorColor::Grey,
+        DoorColor::Yellow,
+        DoorColor::Green,
+        DoorColor::Red,
+    ];
+    let facings = [Facing::Left, Facing::Right, Facing::Up, Facing::Down];
+    Some((*colors.get(n / 4)?, facings[n % 4]))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Door {
+    // Bit index into the door bitfield, as given to the door PLM
+    pub index: u16,
+    pub area: Area,
+    pub color: DoorColor,
+    pub facing: Facing,
+    // Header pointer of the room the door is in
+    pub room: u16,
+}
+
+impl Door {
+    pub fn name(&self) -> String {
+        let room = match room::room_name(self.room) {
+            Some(name) => name.to_string(),
+            None => format!("room ${:04X}", self.room),
+        };
+        format!("{}, facing {:?}", room, self.facing)
+    }
+}
+
+fn byte(bank: &[u8], address: u16) -> Option<u8> {
+    bank.get(address.checked_sub(0x8000)? as usize).copied()
+}
+
+fn word(bank: &[u8], address: u16) -> Option<u16> {
+    Some(u16::from_le_bytes([
+        byte(bank, address)?,
+        byte(bank, address.checked_add(1)?)?,
+    ]))
+}
+
+// Argument bytes between a state condition and its state pointer, None for
+// conditions we don't know
+fn condition_args(condition: u16) -> Option<u16> {
+    match condition {
+        // Came in through a particular door
+        0xE5EB => Some(2),
+        // Event set, boss dead
+        0xE612 | 0xE629 => Some(1),
+        // Tourian boss dead, morph, morph and missiles, power bombs, speed
+        0xE5FF | 0xE640 | 0xE652 | 0xE669 | 0xE678 => Some(0),
+        _ => None,
+    }
+}
+
+// The PLM population of every state of a room, None if the header doesn't
+// look like one
+fn room_plm_sets(rooms: &[u8], room: u16) -> Option<Vec<u16>> {
+    let mut sets = vec![];
+    let mut at = room.checked_add(ROOM_STATES)?;
+    loop {
+        let condition = word(rooms, at)?;
+        if condition == STATE_DEFAULT {
+            sets.push(word(rooms, at.checked_add(2 + STATE_PLMS)?)?);
+            return Some(sets);
+        }
+        let args = condition_args(condition)?;
+        let state = word(rooms, at.checked_add(2 + args)?)?;
+        sets.push(word(rooms, state.checked_add(STATE_PLMS)?)?);
+        at = at.checked_add(4 + args)?;
+    }
+}
+
+// The rooms a room's doors lead to. The list runs until something that
+// isn't a pointer, in vanilla the next room's header.
+fn neighbours(rooms: &[u8], doors: &[u8], room: u16) -> Vec<u16> {
+    let mut r = vec![];
+    let list = match room.checked_add(ROOM_DOORS).and_then(|a| word(rooms, a)) {
+        Some(list) => list,
+        None => return r,
+    };
+    for i in 0..MAX_ROOM_DOORS {
+        let door = match list.checked_add(2 * i).and_then(|a| word(rooms, a)) {
+            Some(door) if door >= 0x8000 => door,
+            _ => break,
+        };
+        // Elevators have doors that lead nowhere
+        if let Some(to) = word(doors, door).filter(|to| *to >= 0x8000) {
+            r.push(to);
+        }
+    }
+    r
+}
+
+// The door PLMs in a PLM population, as (id, argument)
+fn plm_set_doors(rooms: &[u8], set: u16) -> Vec<(u16, u16)> {
+    let mut r = vec![];
+    let mut at = set;
+    while let (Some(id), Some(argument)) = (
+        word(rooms, at),
+        at.checked_add(4).and_then(|a| word(rooms, a)),
+    ) {
+        if id == 0 {
+            break;
+        }
+        if door_plm(id).is_some() {
+            r.push((id, argument));
+        }
+        at = match at.checked_add(6) {
+            Some(at) => at,
+            None => break,
+        };
+    }
+    r
+}
+
+// Walk the rooms in banks $8F and $83 (each starting at $8000) and collect
+// every door with a bit, in bit order
+pub fn door_table(rooms: &[u8], doors: &[u8]) -> Vec<Door> {
+    let mut table: Vec<Door> = vec![];
+    let mut seen = BTreeSet::new();
+    let mut queue = START_ROOMS.to_vec();
+    while let Some(room) = queue.pop() {
+        if !seen.insert(room) {
+            continue;
+        }
+        let area = match room
+            .checked_add(ROOM_AREA)
+            .and_then(|a| byte(rooms, a))
+            .and_then(|a| room::area_from_index(a as u16))
+        {
+            Some(area) => area,
+            None => continue,
+        };
+        let sets = match room_plm_sets(rooms, room) {
+            Some(sets) => sets,
+            None => continue,
+        };
+        for set in sets.into_iter().collect::<BTreeSet<_>>() {
+            for (id, argument) in plm_set_doors(rooms, set) {
+                let index = argument & DOOR_INDEX_MASK;
+                if argument & NO_DOOR_BIT != 0
+                    || index as usize >= DOOR_BITS_LEN * 8
+                    || table.iter().any(|d| d.index == index)
+                {
+                    continue;
+                }
+                let (color, facing) = door_plm(id).unwrap();
+                table.push(Door {
+                    index,
+                    area,
+                    color,
+                    facing,
+                    room,
+                });
+            }
+        }
+        queue.extend(neighbours(rooms, doors, room));
+    }
+    table.sort_by_key(|d| d.index);
+    table
+}
+
+pub fn get_door_table(client: &mut SyncClient) -> Result<Vec<Door>, Box<dyn Error>> {
+    let rooms = rom::read_rom(client, ROOM_BANK, BANK_SIZE)?;
+    let doors = rom::read_rom(client, DOOR_BANK, BANK_SIZE)?;
+    Ok(door_table(&rooms, &doors))
+}
+
+// The doors in `area`, or anywhere, optionally only of one color
+pub fn matching(
+    table: &[Door],
+    area: Option<Area>,
+    color: Option<DoorColor>,
+) -> impl Iterator<Item = &Door> {
+    table.iter().filter(move |d| {
+        area.map(|a| a == d.area).unwrap_or(true) && color.map(|c| c == d.color).unwrap_or(true)
+    })
+}
+
+// Open doors by area, the same shape as `Samus::bosses`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Doors {
+    pub open: BTreeMap<Area, BTreeSet<u16>>,
+    // Open bits with no door in the table, kept so they're written back as
+    // they were
+    pub unknown: BTreeSet<u16>,
+}
+
+impl Doors {
+    pub fn from_bits(bits: &[u8], table: &[Door]) -> Doors {
+        let mut doors = Doors::default();
+        for index in 0..(bits.len() * 8) as u16 {
+            if bits[index as usize / 8] & (1 << (index % 8)) == 0 {
+                continue;
+            }
+            match table.iter().find(|d| d.index == index) {
+                Some(door) => {
+                    doors.open.entry(door.area).or_default().insert(index);
+                }
+                None => {
+                    doors.unknown.insert(index);
+                }
+            }
+        }
+        doors
+    }
+
+    pub fn to_bits(&self) -> [u8; DOOR_BITS_LEN] {
+        let mut bits = [0; DOOR_BITS_LEN];
+        for index in self.open.values().flatten().chain(&self.unknown) {
+            bits[*index as usize / 8] |= 1 << (index % 8);
+        }
+        bits
+    }
+
+    pub fn is_open(&self, door: &Door) -> bool {
+        self.open
+            .get(&door.area)
+            .map(|open| open.contains(&door.index))
+            .unwrap_or(false)
+    }
+
+    pub fn set_open(&mut self, door: &Door, open: bool) {
+        let area = self.open.entry(door.area).or_default();
+        if open {
+            area.insert(door.index);
+        } else {
+            area.remove(&door.index);
+            if area.is_empty() {
+                self.open.remove(&door.area);
+            }
+        }
+    }
+
+    // Close every door in `area`
+    pub fn reset_area(&mut self, area: Area) {
+        self.open.remove(&area);
+    }
+
+    pub fn overwrite_asm(&self) -> Vec<u8> {
+        let mut r = Vec::new();
+        for (i, word) in self.to_bits().chunks(2).enumerate() {
+            r.extend_from_slice(&lda_immediate_u16(u16::from_le_bytes([word[0], word[1]])));
+            r.extend_from_slice(&sta_long(0x7E_0000 + DOOR_BITS as u32 + 2 * i as u32));
+        }
+        r
+    }
+}
+
+pub fn get_door_bits(client: &mut SyncClient) -> Result<Vec<u8>, Box<dyn Error>> {
+    client.get_address(WRAM + DOOR_BITS as u32, DOOR_BITS_LEN)
+}
+
+pub fn get_doors(client: &mut SyncClient, table: &[Door]) -> Result<Doors, Box<dyn Error>> {
+    Ok(Doors::from_bits(&get_door_bits(client)?, table))
+}
+
+// Set or clear one door's bit, leaving the others alone
+pub fn door_asm(index: u16, open: bool) -> Vec<u8> {
+    let address = 0x7E_0000 + DOOR_BITS as u32 + (index / 8) as u32;
+    let mask = 1 << (index % 8);
+    let mut r = Vec::new();
+    // sep #$20
+    r.push(0xe2);
+    r.push(0x20);
+    r.extend_from_slice(&lda_long(address));
+    if open {
+        r.extend_from_slice(&ora_immediate_u8(mask));
+    } else {
+        r.extend_from_slice(&and_immediate_u8(!mask));
+    }
+    r.extend_from_slice(&sta_long(address));
+    // rep #$20
+    r.push(0xc2);
+    r.push(0x20);
+    r
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DoorsArgs {
+    #[clap(subcommand)]
+    action: DoorsAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum DoorsAction {
+    /// List the doors by area, and whether they're open
+    List {
+        /// Only doors in this area
+        #[clap(long, arg_enum)]
+        area: Option<Area>,
+    },
+    /// Open doors by bit index, or every door in an area or of a color
+    Open {
+        doors: Vec<u16>,
+        /// Every door in this area
+        #[clap(long, arg_enum)]
+        area: Option<Area>,
+        /// Every door of this color, in --area if given
+        #[clap(long, arg_enum)]
+        color: Option<DoorColor>,
+    },
+    /// Close doors by bit index, or every door in an area or of a color
+    Close {
+        doors: Vec<u16>,
+        /// Every door in this area
+        #[clap(long, arg_enum)]
+        area: Option<Area>,
+        /// Every door of this color, in --area if given
+        #[clap(long, arg_enum)]
+        color: Option<DoorColor>,
+    },
+    /// Close every door in an area
+    Reset {
+        #[clap(arg_enum)]
+        area: Area,
+    },
+}
+
+fn run_doors(
+    client: &mut SyncClient,
+    doors: impl Iterator<Item = u16>,
+    open: bool,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    let mut payload = Payload::from_args(payload_args);
+    for index in doors {
+        if index as usize >= DOOR_BITS_LEN * 8 {
+            return Err(format!("door index {} is out of range", index).into());
+        }
+        payload.push(&door_asm(index, open));
+    }
+    if payload.is_empty() {
+        eprintln!("no doors matched");
+        return Ok(());
+    }
+    payload.run(client)
+}
+
+// The explicit indices plus every door matching the area and color
+fn select(
+    client: &mut SyncClient,
+    mut doors: Vec<u16>,
+    area: Option<Area>,
+    color: Option<DoorColor>,
+) -> Result<Vec<u16>, Box<dyn Error>> {
+    if area.is_some() || color.is_some() {
+        let table = get_door_table(client)?;
+        doors.extend(matching(&table, area, color).map(|d| d.index));
+    } else if doors.is_empty() {
+        return Err("give door indices, --area or --color".into());
+    }
+    Ok(doors)
+}
+
+pub fn run(
+    client: &mut SyncClient,
+    args: DoorsArgs,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    match args.action {
+        DoorsAction::List { area } => {
+            let table = get_door_table(client)?;
+            let doors = get_doors(client, &table)?;
+            let mut last_area = None;
+            for door in matching(&table, area, None) {
+                if last_area != Some(door.area) {
+                    println!("{:?}:", door.area);
+                    last_area = Some(door.area);
+                }
+                println!(
+                    "  {:3} {:6} {:6} {}",
+                    door.index,
+                    format!("{:?}", door.color),
+                    if doors.is_open(door) {
+                        "open"
+                    } else {
+                        "closed"
+                    },
+                    door.name()
+                );
+            }
+            if area.is_none() && !doors.unknown.is_empty() {
+                println!("open, not in the door table: {:?}", doors.unknown);
+            }
+        }
+        DoorsAction::Open { doors, area, color } => {
+            let doors = select(client, doors, area, color)?;
+            run_doors(client, doors.into_iter(), true, payload_args)?
+        }
+        DoorsAction::Close { doors, area, color } => {
+            let doors = select(client, doors, area, color)?;
+            run_doors(client, doors.into_iter(), false, payload_args)?
+        }
+        DoorsAction::Reset { area } => {
+            let doors = select(client, vec![], Some(area), None)?;
+            run_doors(client, doors.into_iter(), false, payload_args)?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Bus, MemoryBus};
+
+    fn door(index: u16, area: Area, color: DoorColor) -> Door {
+        Door {
+            index,
+            area,
+            color,
+            facing: Facing::Left,
+            room: 0x91F8,
+        }
+    }
+
+    fn table() -> Vec<Door> {
+        vec![
+            door(1, Area::Brinstar, DoorColor::Red),
+            door(2, Area::Brinstar, DoorColor::Green),
+            door(9, Area::Brinstar, DoorColor::Red),
+            door(12, Area::Maridia, DoorColor::Red),
+            door(13, Area::Maridia, DoorColor::Yellow),
+        ]
+    }
+
+    fn bits(bus: &mut MemoryBus) -> u16 {
+        bus.read_u16(0x7E_0000 + DOOR_BITS as u32)
+    }
+
+    #[test]
+    fn grouped_by_area() {
+        let doors = Doors::from_bits(&[0x06, 0x31], &table());
+        assert_eq!(doors.open[&Area::Brinstar], BTreeSet::from([1, 2]));
+        assert_eq!(doors.open[&Area::Maridia], BTreeSet::from([12, 13]));
+        assert_eq!(doors.unknown, BTreeSet::from([8]));
+        assert_eq!(doors.to_bits()[..2], [0x06, 0x31]);
+    }
+
+    #[test]
+    fn overwrite_lands_in_wram() {
+        let mut bus = MemoryBus::new();
+        bus.load(0x7E_0000 + DOOR_BITS as u32, &[0xFF; DOOR_BITS_LEN]);
+        let table = table();
+        let mut doors = Doors::from_bits(&[0xFF; DOOR_BITS_LEN], &table);
+        doors.reset_area(Area::Brinstar);
+        doors.set_open(&table[4], false);
+        let mut payload = Payload::new();
+        payload.push(&doors.overwrite_asm());
+        payload.run_on(&mut bus);
+        assert_eq!(bits(&mut bus), 0xDDF9);
+        for i in 2..DOOR_BITS_LEN as u32 {
+            assert_eq!(bus.read(0x7E_0000 + DOOR_BITS as u32 + i), 0xFF);
+        }
+        assert_eq!(bus.read(0x80_0000 + DOOR_BITS as u32), 0);
+    }
+
+    #[test]
+    fn open_area_of_one_color() {
+        let mut bus = MemoryBus::new();
+        bus.load(0x7E_0000 + DOOR_BITS as u32, &[0x00, 0x20]);
+        let mut payload = Payload::new();
+        for door in matching(&table(), Some(Area::Brinstar), Some(DoorColor::Red)) {
+            payload.push(&door_asm(door.index, true));
+        }
+        payload.run_on(&mut bus);
+        assert_eq!(bits(&mut bus), 0x2202);
+    }
+
+    #[test]
+    fn reset_one_area() {
+        let mut bus = MemoryBus::new();
+        bus.load(0x7E_0000 + DOOR_BITS as u32, &[0xFF, 0xFF]);
+        let mut payload = Payload::new();
+        for door in matching(&table(), Some(Area::Maridia), None) {
+            payload.push(&door_asm(door.index, false));
+        }
+        payload.run_on(&mut bus);
+        assert_eq!(bits(&mut bus), 0xCFFF);
+    }
+
+    // Put `data` at `address` in a bank image
+    fn put(bank: &mut [u8], address: u16, data: &[u8]) {
+        let at = address as usize - 0x8000;
+        bank[at..at + data.len()].copy_from_slice(data);
+    }
+
+    fn words(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn table_from_room_data() {
+        let mut rooms = vec![0; BANK_SIZE];
+        let mut doors = vec![0; BANK_SIZE];
+        // The landing site, in Brinstar for the test, leads to $9400
+        put(&mut rooms, 0x91F8, &[0, 1, 0, 0, 1, 1, 0, 0, 0]);
+        put(&mut rooms, 0x91F8 + 9, &words(&[0x9300, STATE_DEFAULT]));
+        put(&mut rooms, 0x91F8 + 13 + 20, &words(&[0x9500]));
+        put(&mut rooms, 0x9300, &words(&[0x8100, 0x8200, 0x0001]));
+        put(&mut doors, 0x8100, &words(&[0x9400]));
+        // An elevator
+        put(&mut doors, 0x8200, &words(&[0x0000]));
+        // A red door facing left on bit 5
+        put(&mut rooms, 0x9500, &words(&[0xC88A, 0x0101, 0x0005, 0]));
+        // $9400 in Maridia has an event state and a default state
+        put(&mut rooms, 0x9400, &[1, 4, 0, 0, 1, 1, 0, 0, 0]);
+        put(&mut rooms, 0x9400 + 9, &words(&[0x9350, 0xE612]));
+        put(&mut rooms, 0x9400 + 13, &[0x01, 0x60, 0x94]);
+        put(&mut rooms, 0x9400 + 16, &words(&[STATE_DEFAULT]));
+        put(&mut rooms, 0x9460 + 20, &words(&[0x9520]));
+        put(&mut rooms, 0x9400 + 18 + 20, &words(&[0x9540]));
+        put(&mut rooms, 0x9350, &words(&[0x8110]));
+        put(&mut doors, 0x8110, &words(&[0x91F8]));
+        // A green door facing right on bit 7, a grey door facing down on bit
+        // 3 opened by killing enemies, and one that never stays open
+        put(
+            &mut rooms,
+            0x9520,
+            &words(&[0xC878, 0, 7, 0xC854, 0, 0x0C03, 0xC85A, 0, 0x8004, 0]),
+        );
+        // The same door again in the default state
+        put(&mut rooms, 0x9540, &words(&[0xC878, 0, 7, 0]));
+        let table = door_table(&rooms, &doors);
+        assert_eq!(
+            table,
+            vec![
+                Door {
+                    index: 3,
+                    area: Area::Maridia,
+                    color: DoorColor::Grey,
+                    facing: Facing::Down,
+                    room: 0x9400,
+                },
+                Door {
+                    index: 5,
+                    area: Area::Brinstar,
+                    color: DoorColor::Red,
+                    facing: Facing::Left,
+                    room: 0x91F8,
+                },
+                Door {
+                    index: 7,
+                    area: Area::Maridia,
+                    color: DoorColor::Green,
+                    facing: Facing::Right,
+                    room: 0x9400,
+                },
+            ]
+        );
+        assert_eq!(table[1].name(), "Landing Site, facing Left");
+        assert_eq!(table[0].name(), "room $9400, facing Down");
+    }
+}
@@ -0,0 +1,244 @@
+//! The active enemy table.
+//!
+//! Every enemy in the room gets a 0x40 byte slot starting at $0F78. The ID is
+//! the pointer to the enemy's header in bank $A0. Only the bosses and
+//! Metroids are named in `ENEMIES`, everything else is listed by its ID.
+
+use crate::payload::{Payload, PayloadArgs};
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+
+pub const ENEMY_TABLE: u16 = 0x0F78;
+pub const ENEMY_SIZE: u16 = 0x40;
+pub const MAX_ENEMIES: u16 = 32;
+pub const ENEMY_COUNT: u16 = 0x0E4E;
+
+// Offsets into an enemy slot
+const ID: u16 = 0x00;
+const X_POSITION: u16 = 0x02;
+const Y_POSITION: u16 = 0x06;
+const PROPERTIES: u16 = 0x0E;
+const AI_HANDLER: u16 = 0x12;
+const HEALTH: u16 = 0x14;
+const FROZEN_TIMER: u16 = 0x26;
+
+// Properties bit that has the game remove the enemy, no death animation
+const DELETE: u16 = 0x0200;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EnemyInfo {
+    pub id: u16,
+    pub name: &'static str,
+    pub boss: bool,
+}
+
+const fn enemy(id: u16, name: &'static str, boss: bool) -> EnemyInfo {
+    EnemyInfo { id, name, boss }
+}
+
+// Not a full enemy list, just the ones worth calling out by name
+pub static ENEMIES: &[EnemyInfo] = &[
+    enemy(0xDD7F, "Metroid", false),
+    enemy(0xDDBF, "Crocomire", true),
+    enemy(0xDE3F, "Draygon", true),
+    enemy(0xDF3F, "Spore Spawn", true),
+    enemy(0xE13F, "Ceres Ridley", true),
+    enemy(0xE17F, "Ridley", true),
+    enemy(0xE2BF, "Kraid", true),
+    enemy(0xE4BF, "Phantoon", true),
+    enemy(0xEC3F, "Mother Brain (body)", true),
+    enemy(0xEC7F, "Mother Brain", true),
+    enemy(0xEEFF, "Bomb Torizo", true),
+    enemy(0xEF7F, "Golden Torizo", true),
+    enemy(0xF293, "Botwoon", true),
+];
+
+pub fn enemy_info(id: u16) -> Option<&'static EnemyInfo> {
+    ENEMIES.iter().find(|e| e.id == id)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Enemy {
+    pub slot: u16,
+    pub id: u16,
+    pub x_position: u16,
+    pub y_position: u16,
+    pub hp: u16,
+    pub properties: u16,
+    pub ai_handler: u16,
+    pub frozen_timer: u16,
+}
+
+impl Enemy {
+    fn from_bytes(slot: u16, data: &[u8]) -> Enemy {
+        let word =
+            |offset: u16| u16::from_le_bytes([data[offset as usize], data[offset as usize + 1]]);
+        Enemy {
+            slot,
+            id: word(ID),
+            x_position: word(X_POSITION),
+            y_position: word(Y_POSITION),
+            hp: word(HEALTH),
+            properties: word(PROPERTIES),
+            ai_handler: word(AI_HANDLER),
+            frozen_timer: word(FROZEN_TIMER),
+        }
+    }
+
+    pub fn info(&self) -> Option<&'static EnemyInfo> {
+        enemy_info(self.id)
+    }
+
+    pub fn is_boss(&self) -> bool {
+        self.info().map(|e| e.boss).unwrap_or(false)
+    }
+}
+
+fn slot_address(slot: u16, offset: u16) -> u16 {
+    ENEMY_TABLE + slot * ENEMY_SIZE + offset
+}
+
+pub fn get_enemies(client: &mut SyncClient) -> Result<Vec<Enemy>, Box<dyn Error>> {
+    let count = get_u16(client, WRAM + ENEMY_COUNT as u32)?.min(MAX_ENEMIES);
+    if count == 0 {
+        return Ok(vec![]);
+    }
+    let data = client.get_address(WRAM + ENEMY_TABLE as u32, (count * ENEMY_SIZE) as usize)?;
+    Ok(data
+        .chunks(ENEMY_SIZE as usize)
+        .enumerate()
+        .map(|(slot, data)| Enemy::from_bytes(slot as u16, data))
+        .filter(|e| e.id != 0)
+        .collect())
+}
+
+pub fn set_hp_asm(slot: u16, hp: u16) -> Vec<u8> {
+    let mut r = Vec::new();
+    r.extend_from_slice(&lda_immediate_u16(hp));
+    r.extend_from_slice(&sta_absolute(slot_address(slot, HEALTH)));
+    r
+}
+
+// Freeze the enemy like the ice beam does, for as long as the timer allows
+pub fn freeze_asm(slot: u16) -> Vec<u8> {
+    let mut r = Vec::new();
+    r.extend_from_slice(&lda_immediate_u16(0xFFFF));
+    r.extend_from_slice(&sta_absolute(slot_address(slot, FROZEN_TIMER)));
+    r
+}
+
+pub fn unfreeze_asm(slot: u16) -> Vec<u8> {
+    stz_absolute(slot_address(slot, FROZEN_TIMER)).to_vec()
+}
+
+// Bosses run their own death sequence once their health hits zero, which
+// also sets their defeated bit. Anything else is just deleted.
+pub fn kill_asm(enemy: &Enemy) -> Vec<u8> {
+    if enemy.is_boss() {
+        return set_hp_asm(enemy.slot, 0);
+    }
+    let address = slot_address(enemy.slot, PROPERTIES);
+    let mut r = Vec::new();
+    r.extend_from_slice(&lda_addr(address));
+    r.extend_from_slice(&ora_immediate_u16(DELETE));
+    r.extend_from_slice(&sta_absolute(address));
+    r
+}
+
+#[derive(clap::Args, Debug)]
+pub struct EnemiesArgs {
+    #[clap(subcommand)]
+    action: EnemiesAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum EnemiesAction {
+    /// List the enemies in the current room by ID, bosses and Metroids by name
+    List,
+    /// Set an enemy's health
+    Hp { slot: u16, hp: u16 },
+    /// Freeze enemies in place
+    Freeze { slots: Vec<u16> },
+    /// Thaw frozen enemies
+    Unfreeze { slots: Vec<u16> },
+    /// Kill enemies, or every enemy in the room with --all
+    Kill {
+        slots: Vec<u16>,
+        #[clap(long)]
+        all: bool,
+    },
+}
+
+// The enemies in `slots`, erroring on empty slots
+fn find(enemies: &[Enemy], slots: &[u16]) -> Result<Vec<Enemy>, Box<dyn Error>> {
+    slots
+        .iter()
+        .map(|slot| {
+            enemies
+                .iter()
+                .find(|e| e.slot == *slot)
+                .copied()
+                .ok_or_else(|| format!("no enemy in slot {}", slot).into())
+        })
+        .collect()
+}
+
+pub fn run(
+    client: &mut SyncClient,
+    args: EnemiesArgs,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    let enemies = get_enemies(client)?;
+    let mut payload = Payload::from_args(payload_args);
+    match args.action {
+        EnemiesAction::List => {
+            for enemy in &enemies {
+                let name = enemy.info().map(|info| info.name).unwrap_or_default();
+                println!(
+                    "{:2} ${:04X} {:20} ({:4}, {:4}) hp {:5}{}",
+                    enemy.slot,
+                    enemy.id,
+                    name,
+                    enemy.x_position,
+                    enemy.y_position,
+                    enemy.hp,
+                    if enemy.frozen_timer != 0 {
+                        " frozen"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            return Ok(());
+        }
+        EnemiesAction::Hp { slot, hp } => {
+            let enemy = find(&enemies, &[slot])?[0];
+            payload.push(&set_hp_asm(enemy.slot, hp));
+        }
+        EnemiesAction::Freeze { slots } => {
+            for enemy in find(&enemies, &slots)? {
+                payload.push(&freeze_asm(enemy.slot));
+            }
+        }
+        EnemiesAction::Unfreeze { slots } => {
+            for enemy in find(&enemies, &slots)? {
+                payload.push(&unfreeze_asm(enemy.slot));
+            }
+        }
+        EnemiesAction::Kill { slots, all } => {
+            let targets = if all {
+                enemies.clone()
+            } else {
+                find(&enemies, &slots)?
+            };
+            for enemy in &targets {
+                payload.push(&kill_asm(enemy));
+            }
+        }
+    }
+    if payload.is_empty() {
+        return Ok(());
+    }
+    payload.run(client)
+}
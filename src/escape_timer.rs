@@ -0,0 +1,328 @@
+//! The Ceres and Zebes escape timer.
+//!
+//! The timer is three BCD bytes: centiseconds at $0945, seconds at $0946 and
+//! minutes at $0947. Adding and subtracting happens in decimal mode on the
+//! SNES against whatever the timer is at that moment, so the frames it takes
+//! to get the payload there aren't lost.
+//!
+//! $0943 is the timer's status, zero when there's no timer. The game only
+//! counts down and draws the timer while it's nonzero, so holding the timer
+//! clears it and puts it back afterwards. The timer is hidden meanwhile.
+
+use crate::payload::{Payload, PayloadArgs};
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+use std::fmt;
+
+pub const TIMER_CENTISECONDS: u16 = 0x0945;
+pub const TIMER_SECONDS: u16 = 0x0946;
+pub const TIMER_MINUTES: u16 = 0x0947;
+pub const TIMER_STATUS: u16 = 0x0943;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EscapeTimer {
+    pub minutes: u8,
+    pub seconds: u8,
+    pub centiseconds: u8,
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+impl EscapeTimer {
+    // From the three timer bytes, centiseconds first
+    pub fn from_bytes(bytes: &[u8]) -> EscapeTimer {
+        EscapeTimer {
+            centiseconds: from_bcd(bytes[0]),
+            seconds: from_bcd(bytes[1]),
+            minutes: from_bcd(bytes[2]),
+        }
+    }
+
+    // Parse mm:ss:cc, mm:ss.cc or mm:ss
+    pub fn parse(s: &str) -> Result<EscapeTimer, Box<dyn Error>> {
+        let parts = s
+            .split([':', '.'])
+            .map(|p| p.parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let timer = match parts[..] {
+            [minutes, seconds] => EscapeTimer {
+                minutes,
+                seconds,
+                centiseconds: 0,
+            },
+            [minutes, seconds, centiseconds] => EscapeTimer {
+                minutes,
+                seconds,
+                centiseconds,
+            },
+            _ => return Err(format!("can't parse {:?} as mm:ss:cc", s).into()),
+        };
+        if timer.minutes > 99 || timer.seconds > 59 || timer.centiseconds > 99 {
+            return Err(format!("{:?} is out of range", s).into());
+        }
+        Ok(timer)
+    }
+
+    pub fn set_asm(self) -> Vec<u8> {
+        let mut r = Vec::new();
+        // sep #$20
+        r.push(0xe2);
+        r.push(0x20);
+        for (address, value) in [
+            (TIMER_CENTISECONDS, self.centiseconds),
+            (TIMER_SECONDS, self.seconds),
+            (TIMER_MINUTES, self.minutes),
+        ] {
+            r.extend_from_slice(&lda_immediate_u8(to_bcd(value)));
+            r.extend_from_slice(&sta_absolute(address));
+        }
+        // rep #$20
+        r.push(0xc2);
+        r.push(0x20);
+        r
+    }
+
+    // Add self to the running timer, stopping at 99:59:99
+    pub fn add_asm(self) -> Vec<u8> {
+        let mut r = Vec::new();
+        // sep #$20
+        r.push(0xe2);
+        r.push(0x20);
+        r.extend_from_slice(&sed());
+        r.extend_from_slice(&clc());
+        r.extend_from_slice(&lda_addr(TIMER_CENTISECONDS));
+        r.extend_from_slice(&adc_immediate_u8(to_bcd(self.centiseconds)));
+        r.extend_from_slice(&sta_absolute(TIMER_CENTISECONDS));
+        r.extend_from_slice(&lda_addr(TIMER_SECONDS));
+        r.extend_from_slice(&adc_immediate_u8(to_bcd(self.seconds)));
+        // Seconds carry at 60 rather than 100. Past 99 A holds the sum - 100
+        // and subtracting 60 with a borrow leaves sum - 60 all the same.
+        r.extend_from_slice(&bcs(4));
+        r.extend_from_slice(&cmp_immediate_u8(0x60));
+        r.extend_from_slice(&bcc(4));
+        r.extend_from_slice(&sec());
+        r.extend_from_slice(&sbc_immediate_u8(0x60));
+        r.extend_from_slice(&sec());
+        r.extend_from_slice(&sta_absolute(TIMER_SECONDS));
+        r.extend_from_slice(&lda_addr(TIMER_MINUTES));
+        r.extend_from_slice(&adc_immediate_u8(to_bcd(self.minutes)));
+        // Out of minutes, max everything out
+        r.extend_from_slice(&bcc(10));
+        r.extend_from_slice(&lda_immediate_u8(0x59));
+        r.extend_from_slice(&sta_absolute(TIMER_SECONDS));
+        r.extend_from_slice(&lda_immediate_u8(0x99));
+        r.extend_from_slice(&sta_absolute(TIMER_CENTISECONDS));
+        r.extend_from_slice(&sta_absolute(TIMER_MINUTES));
+        r.extend_from_slice(&cld());
+        // rep #$20
+        r.push(0xc2);
+        r.push(0x20);
+        r
+    }
+
+    // Take self off the running timer, stopping at 00:00:00
+    pub fn sub_asm(self) -> Vec<u8> {
+        let mut r = Vec::new();
+        // sep #$20
+        r.push(0xe2);
+        r.push(0x20);
+        r.extend_from_slice(&sed());
+        r.extend_from_slice(&sec());
+        r.extend_from_slice(&lda_addr(TIMER_CENTISECONDS));
+        r.extend_from_slice(&sbc_immediate_u8(to_bcd(self.centiseconds)));
+        r.extend_from_slice(&sta_absolute(TIMER_CENTISECONDS));
+        r.extend_from_slice(&lda_addr(TIMER_SECONDS));
+        r.extend_from_slice(&sbc_immediate_u8(to_bcd(self.seconds)));
+        // On a borrow A holds 100 + the difference, we want 60 + it
+        r.extend_from_slice(&bcs(4));
+        r.extend_from_slice(&sec());
+        r.extend_from_slice(&sbc_immediate_u8(0x40));
+        r.extend_from_slice(&clc());
+        r.extend_from_slice(&sta_absolute(TIMER_SECONDS));
+        r.extend_from_slice(&lda_addr(TIMER_MINUTES));
+        r.extend_from_slice(&sbc_immediate_u8(to_bcd(self.minutes)));
+        // Ran out, zero everything
+        r.extend_from_slice(&bcs(8));
+        r.extend_from_slice(&stz_absolute(TIMER_CENTISECONDS));
+        r.extend_from_slice(&stz_absolute(TIMER_SECONDS));
+        r.extend_from_slice(&lda_immediate_u8(0));
+        r.extend_from_slice(&sta_absolute(TIMER_MINUTES));
+        r.extend_from_slice(&cld());
+        // rep #$20
+        r.push(0xc2);
+        r.push(0x20);
+        r
+    }
+}
+
+impl fmt::Display for EscapeTimer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}:{:02}",
+            self.minutes, self.seconds, self.centiseconds
+        )
+    }
+}
+
+// Set the timer status byte, 0 stops the timer
+pub fn set_status_asm(status: u8) -> Vec<u8> {
+    let mut r = Vec::new();
+    // sep #$20
+    r.push(0xe2);
+    r.push(0x20);
+    r.extend_from_slice(&lda_immediate_u8(status));
+    r.extend_from_slice(&sta_absolute(TIMER_STATUS));
+    // rep #$20
+    r.push(0xc2);
+    r.push(0x20);
+    r
+}
+
+pub fn get_timer_status(client: &mut SyncClient) -> Result<u8, Box<dyn Error>> {
+    Ok(client.get_address(WRAM + TIMER_STATUS as u32, 1)?[0])
+}
+
+pub fn get_escape_timer(client: &mut SyncClient) -> Result<EscapeTimer, Box<dyn Error>> {
+    let data = client.get_address(WRAM + TIMER_CENTISECONDS as u32, 3)?;
+    Ok(EscapeTimer::from_bytes(&data))
+}
+
+#[derive(clap::Args, Debug)]
+pub struct EscapeTimerArgs {
+    #[clap(subcommand)]
+    action: EscapeTimerAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum EscapeTimerAction {
+    /// Print the escape timer
+    Show,
+    /// Set the escape timer, as mm:ss:cc
+    Set { time: String },
+    /// Add time to the escape timer, as mm:ss:cc
+    Add { time: String },
+    /// Take time off the escape timer, as mm:ss:cc
+    Sub { time: String },
+    /// Stop the timer until enter or ctrl-c is pressed
+    Hold,
+}
+
+pub fn run(
+    client: &mut SyncClient,
+    args: EscapeTimerArgs,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    match args.action {
+        EscapeTimerAction::Show => println!("{}", get_escape_timer(client)?),
+        EscapeTimerAction::Set { time } => {
+            Payload::run_block(client, &EscapeTimer::parse(&time)?.set_asm(), payload_args)?
+        }
+        EscapeTimerAction::Add { time } => {
+            Payload::run_block(client, &EscapeTimer::parse(&time)?.add_asm(), payload_args)?
+        }
+        EscapeTimerAction::Sub { time } => {
+            Payload::run_block(client, &EscapeTimer::parse(&time)?.sub_asm(), payload_args)?
+        }
+        EscapeTimerAction::Hold => {
+            let status = get_timer_status(client)?;
+            if status == 0 {
+                return Err("the escape timer isn't running".into());
+            }
+            let resume = crate::enter_or_ctrl_c()?;
+            Payload::run_block(client, &set_status_asm(0), payload_args)?;
+            println!(
+                "holding the timer at {}, enter or ctrl-c to let it go",
+                get_escape_timer(client)?
+            );
+            resume.recv()?;
+            Payload::run_block(client, &set_status_asm(status), payload_args)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Bus, MemoryBus};
+
+    // Start the timer at `start`, run `block` and read the timer back
+    fn run(start: &str, block: Vec<u8>) -> String {
+        let start = EscapeTimer::parse(start).unwrap();
+        let mut bus = MemoryBus::new();
+        bus.load(
+            0x7E_0000 + TIMER_CENTISECONDS as u32,
+            &[
+                to_bcd(start.centiseconds),
+                to_bcd(start.seconds),
+                to_bcd(start.minutes),
+            ],
+        );
+        let mut payload = Payload::new();
+        payload.push(&block);
+        payload.run_on(&mut bus);
+        let bytes = (0..3)
+            .map(|i| bus.read(0x7E_0000 + TIMER_CENTISECONDS as u32 + i))
+            .collect::<Vec<_>>();
+        EscapeTimer::from_bytes(&bytes).to_string()
+    }
+
+    fn time(s: &str) -> EscapeTimer {
+        EscapeTimer::parse(s).unwrap()
+    }
+
+    #[test]
+    fn set() {
+        assert_eq!(run("01:02:03", time("12:34:56").set_asm()), "12:34:56");
+    }
+
+    #[test]
+    fn add_carries() {
+        assert_eq!(run("00:10:95", time("00:00:10").add_asm()), "00:11:05");
+        // Seconds carry at 60
+        assert_eq!(run("01:50:00", time("00:20:00").add_asm()), "02:10:00");
+        assert_eq!(run("01:59:99", time("00:00:01").add_asm()), "02:00:00");
+        // A carry past 99 seconds still comes out right
+        assert_eq!(run("00:59:00", time("00:59:00").add_asm()), "01:58:00");
+    }
+
+    #[test]
+    fn sub_borrows() {
+        assert_eq!(run("02:10:05", time("00:20:10").sub_asm()), "01:49:95");
+        assert_eq!(run("01:00:00", time("00:00:01").sub_asm()), "00:59:99");
+    }
+
+    #[test]
+    fn add_clamps_at_the_top() {
+        assert_eq!(run("99:50:00", time("00:20:00").add_asm()), "99:59:99");
+        assert_eq!(run("98:00:00", time("02:00:00").add_asm()), "99:59:99");
+    }
+
+    #[test]
+    fn sub_clamps_at_zero() {
+        assert_eq!(run("00:10:00", time("00:20:00").sub_asm()), "00:00:00");
+        assert_eq!(run("00:00:50", time("00:00:51").sub_asm()), "00:00:00");
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let mut bus = MemoryBus::new();
+        bus.load(0x7E_0000 + TIMER_STATUS as u32, &[0x07, 0x55]);
+        let mut payload = Payload::new();
+        payload.push(&set_status_asm(0));
+        payload.run_on(&mut bus);
+        assert_eq!(bus.read_u16(0x7E_0000 + TIMER_STATUS as u32), 0x5500);
+        let mut payload = Payload::new();
+        payload.push(&set_status_asm(7));
+        payload.run_on(&mut bus);
+        assert_eq!(bus.read_u16(0x7E_0000 + TIMER_STATUS as u32), 0x5507);
+    }
+}
@@ -0,0 +1,140 @@
+//! In-game time.
+//!
+//! The game counts IGT as separate frame, second, minute and hour words at
+//! $09DA-$09E0, ticking at 60 frames a second regardless of region.
+
+use crate::payload::{Payload, PayloadArgs};
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+pub const IGT_FRAMES: u16 = 0x09DA;
+pub const IGT_SECONDS: u16 = 0x09DC;
+pub const IGT_MINUTES: u16 = 0x09DE;
+pub const IGT_HOURS: u16 = 0x09E0;
+pub const FRAMES_PER_SECOND: u64 = 60;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Igt {
+    pub hours: u16,
+    pub minutes: u16,
+    pub seconds: u16,
+    pub frames: u16,
+}
+
+impl Igt {
+    pub fn to_duration(self) -> Duration {
+        let seconds = self.hours as u64 * 3600 + self.minutes as u64 * 60 + self.seconds as u64;
+        Duration::from_secs(seconds)
+            + Duration::from_nanos(self.frames as u64 * 1_000_000_000 / FRAMES_PER_SECOND)
+    }
+
+    // Rounds to the nearest frame. The game's counter stops at 99:59:59.59.
+    pub fn from_duration(duration: Duration) -> Igt {
+        let frames = (duration.as_nanos() as u64 * FRAMES_PER_SECOND + 500_000_000) / 1_000_000_000;
+        let max = ((99 * 60 + 59) * 60 + 59) * FRAMES_PER_SECOND + 59;
+        let frames = frames.min(max);
+        Igt {
+            hours: (frames / (FRAMES_PER_SECOND * 3600)) as u16,
+            minutes: (frames / (FRAMES_PER_SECOND * 60) % 60) as u16,
+            seconds: (frames / FRAMES_PER_SECOND % 60) as u16,
+            frames: (frames % FRAMES_PER_SECOND) as u16,
+        }
+    }
+
+    // Parse [[h:]mm:]ss[.ff], where ff is frames
+    pub fn parse(s: &str) -> Result<Igt, Box<dyn Error>> {
+        let (time, frames) = match s.split_once('.') {
+            Some((time, frames)) => (time, frames.parse::<u16>()?),
+            None => (s, 0),
+        };
+        let mut parts = time
+            .split(':')
+            .map(|p| p.parse::<u16>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if parts.is_empty() || parts.len() > 3 {
+            return Err(format!("can't parse {:?} as a time", s).into());
+        }
+        while parts.len() < 3 {
+            parts.insert(0, 0);
+        }
+        let igt = Igt {
+            hours: parts[0],
+            minutes: parts[1],
+            seconds: parts[2],
+            frames,
+        };
+        if igt.hours > 99 || igt.minutes > 59 || igt.seconds > 59 || igt.frames > 59 {
+            return Err(format!("{:?} is out of range", s).into());
+        }
+        Ok(igt)
+    }
+
+    // All four words in one block so the game never sees half an update
+    pub fn to_asm(self) -> Vec<u8> {
+        let mut r = Vec::new();
+        for (address, value) in [
+            (IGT_FRAMES, self.frames),
+            (IGT_SECONDS, self.seconds),
+            (IGT_MINUTES, self.minutes),
+            (IGT_HOURS, self.hours),
+        ] {
+            r.extend_from_slice(&lda_immediate_u16(value));
+            r.extend_from_slice(&sta_absolute(address));
+        }
+        r
+    }
+}
+
+impl fmt::Display for Igt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{:02}:{:02}.{:02}",
+            self.hours, self.minutes, self.seconds, self.frames
+        )
+    }
+}
+
+pub fn get_igt(client: &mut SyncClient) -> Result<Igt, Box<dyn Error>> {
+    let data = client.get_address(WRAM + IGT_FRAMES as u32, 8)?;
+    let word = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+    Ok(Igt {
+        frames: word(0),
+        seconds: word(2),
+        minutes: word(4),
+        hours: word(6),
+    })
+}
+
+#[derive(clap::Args, Debug)]
+pub struct IgtArgs {
+    #[clap(subcommand)]
+    action: IgtAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum IgtAction {
+    /// Print the in-game time
+    Show,
+    /// Set the in-game time, as [[h:]mm:]ss[.frames]
+    Set { time: String },
+}
+
+pub fn run(
+    client: &mut SyncClient,
+    args: IgtArgs,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    match args.action {
+        IgtAction::Show => println!("{}", get_igt(client)?),
+        IgtAction::Set { time } => {
+            let mut payload = Payload::from_args(payload_args);
+            payload.push(&Igt::parse(&time)?.to_asm());
+            payload.run(client)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,188 @@
+//! Controller input.
+//!
+//! $8B holds the buttons held this frame and $8F the ones that went down
+//! this frame, both in the joypad register layout. Polling goes over the
+//! usb2snes connection so it's nowhere near every frame, short presses can
+//! be missed entirely.
+
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+pub const INPUT_HELD: u16 = 0x008B;
+pub const INPUT_NEW: u16 = 0x008F;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Buttons(pub u16);
+
+impl Buttons {
+    pub const B: Buttons = Buttons(0x8000);
+    pub const Y: Buttons = Buttons(0x4000);
+    pub const SELECT: Buttons = Buttons(0x2000);
+    pub const START: Buttons = Buttons(0x1000);
+    pub const UP: Buttons = Buttons(0x0800);
+    pub const DOWN: Buttons = Buttons(0x0400);
+    pub const LEFT: Buttons = Buttons(0x0200);
+    pub const RIGHT: Buttons = Buttons(0x0100);
+    pub const A: Buttons = Buttons(0x0080);
+    pub const X: Buttons = Buttons(0x0040);
+    pub const L: Buttons = Buttons(0x0020);
+    pub const R: Buttons = Buttons(0x0010);
+
+    // In display order
+    pub const ALL: [(Buttons, &'static str); 12] = [
+        (Buttons::UP, "Up"),
+        (Buttons::DOWN, "Down"),
+        (Buttons::LEFT, "Left"),
+        (Buttons::RIGHT, "Right"),
+        (Buttons::SELECT, "Select"),
+        (Buttons::START, "Start"),
+        (Buttons::Y, "Y"),
+        (Buttons::B, "B"),
+        (Buttons::X, "X"),
+        (Buttons::A, "A"),
+        (Buttons::L, "L"),
+        (Buttons::R, "R"),
+    ];
+
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, other: Buttons) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other: Buttons) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Buttons::ALL
+            .into_iter()
+            .filter(move |(b, _)| self.contains(*b))
+            .map(|(_, name)| name)
+    }
+
+    // Parse a combo like "Select+L", ignoring case
+    pub fn parse(s: &str) -> Result<Buttons, String> {
+        let mut buttons = Buttons::default();
+        for name in s.split('+').map(str::trim) {
+            let (button, _) = Buttons::ALL
+                .iter()
+                .find(|(_, n)| n.eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("unknown button {:?} in {:?}", name, s))?;
+            buttons = buttons | *button;
+        }
+        Ok(buttons)
+    }
+
+    // Fixed width, every button always in the same column
+    pub fn display_line(self) -> String {
+        let glyphs = ["^", "v", "<", ">", "Sl", "St", "Y", "B", "X", "A", "L", "R"];
+        Buttons::ALL
+            .iter()
+            .zip(glyphs)
+            .map(|((b, _), glyph)| {
+                if self.contains(*b) {
+                    glyph.to_string()
+                } else {
+                    ".".repeat(glyph.len())
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl std::ops::BitOr for Buttons {
+    type Output = Buttons;
+
+    fn bitor(self, other: Buttons) -> Buttons {
+        Buttons(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Buttons {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "-");
+        }
+        write!(f, "{}", self.names().collect::<Vec<_>>().join("+"))
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Input {
+    pub held: Buttons,
+    pub new: Buttons,
+}
+
+pub fn get_input(client: &mut SyncClient) -> Result<Input, Box<dyn Error>> {
+    let data =
+        client.get_addresses(&[(WRAM + INPUT_HELD as u32, 2), (WRAM + INPUT_NEW as u32, 2)])?;
+    let word = |d: &[u8]| u16::from_le_bytes([d[0], d[1]]);
+    Ok(Input {
+        held: Buttons(word(&data[0])),
+        new: Buttons(word(&data[1])),
+    })
+}
+
+#[derive(clap::Args, Debug)]
+pub struct InputArgs {
+    #[clap(subcommand)]
+    action: InputAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum InputAction {
+    /// Print the buttons held right now
+    Show,
+    /// Keep showing the held buttons until interrupted
+    Watch {
+        /// Milliseconds between polls
+        #[clap(long, default_value = "16")]
+        interval: u64,
+        /// Print a line every time the input changes instead of redrawing one
+        #[clap(long)]
+        log: bool,
+    },
+}
+
+pub fn run(client: &mut SyncClient, args: InputArgs) -> Result<(), Box<dyn Error>> {
+    match args.action {
+        InputAction::Show => {
+            let input = get_input(client)?;
+            println!("held {}, new {}", input.held, input.new);
+        }
+        InputAction::Watch { interval, log } => {
+            let start = Instant::now();
+            let mut last = None;
+            loop {
+                let input = get_input(client)?;
+                if last != Some(input.held) {
+                    if log {
+                        println!(
+                            "{:8.3} {}",
+                            start.elapsed().as_secs_f64(),
+                            input.held.display_line()
+                        );
+                    } else {
+                        print!("\r{}", input.held.display_line());
+                        std::io::stdout().flush()?;
+                    }
+                    last = Some(input.held);
+                }
+                std::thread::sleep(Duration::from_millis(interval));
+            }
+        }
+    }
+    Ok(())
+}
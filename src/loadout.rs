@@ -0,0 +1,214 @@
+//! Loadout files: a `Samus` where every field is optional.
+//!
+//! `samus dump` writes every field, but a loadout only needs the fields it
+//! wants to change, so presets like "RBO after Phantoon" can be a few lines
+//! of TOML that are applied on top of whatever the game currently has.
+
+use crate::bosses::{NamedBoss, NAMED_BOSSES};
+use crate::*;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::path::Path;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Loadout {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hp: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_hp: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    missiles: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_missiles: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    supers: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_supers: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pbs: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_pbs: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    equipped_items: Option<BTreeSet<Item>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    collected_items: Option<BTreeSet<Item>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    equipped_beams: Option<BTreeSet<Beam>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    collected_beams: Option<BTreeSet<Beam>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reserve_hp: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_reserve_hp: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    x_position: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    x_subposition: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    y_position: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    y_subposition: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reserve_mode: Option<ReserveMode>,
+    // Maximums in pickup units, a raw maximum set above wins over these
+    #[serde(skip_serializing_if = "Option::is_none")]
+    etanks: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reserve_tanks: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    missile_packs: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    super_packs: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pb_packs: Option<u16>,
+    // Raw boss bits, only the areas listed are changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bosses: Option<BTreeMap<Area, BTreeSet<Boss>>>,
+    // Exactly these named bosses are defeated, applied after `bosses`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    defeated: Option<BTreeSet<NamedBoss>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    events: Option<BTreeSet<Event>>,
+}
+
+impl From<&Samus> for Loadout {
+    fn from(samus: &Samus) -> Loadout {
+        Loadout {
+            hp: Some(samus.hp),
+            max_hp: Some(samus.max_hp),
+            missiles: Some(samus.missiles),
+            max_missiles: Some(samus.max_missiles),
+            supers: Some(samus.supers),
+            max_supers: Some(samus.max_supers),
+            pbs: Some(samus.pbs),
+            max_pbs: Some(samus.max_pbs),
+            equipped_items: Some(samus.equipped_items.iter().copied().collect()),
+            collected_items: Some(samus.collected_items.iter().copied().collect()),
+            equipped_beams: Some(samus.equipped_beams.iter().copied().collect()),
+            collected_beams: Some(samus.collected_beams.iter().copied().collect()),
+            reserve_hp: Some(samus.reserve_hp),
+            max_reserve_hp: Some(samus.max_reserve_hp),
+            x_position: Some(samus.x_position),
+            x_subposition: Some(samus.x_subposition),
+            y_position: Some(samus.y_position),
+            y_subposition: Some(samus.y_subposition),
+            reserve_mode: Some(samus.reserve_mode),
+            etanks: None,
+            reserve_tanks: None,
+            missile_packs: None,
+            super_packs: None,
+            pb_packs: None,
+            bosses: None,
+            defeated: Some(samus.defeated()),
+            events: Some(samus.events.iter().copied().collect()),
+        }
+    }
+}
+
+impl Loadout {
+    // Overwrite the fields of `samus` this loadout sets, failing if a
+    // pickup count is too big for the game
+    pub fn apply(&self, samus: &mut Samus) -> Result<(), Box<dyn Error>> {
+        fn set<T: Clone>(field: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *field = value.clone();
+            }
+        }
+        // Unknown bits already in the game are kept unless the loadout
+        // lists unknown bits of its own
+        fn set_items<T: Copy + Eq + std::hash::Hash>(
+            field: &mut HashSet<T>,
+            value: &Option<BTreeSet<T>>,
+            is_unknown: fn(T) -> bool,
+        ) {
+            if let Some(value) = value {
+                let mut items: HashSet<T> = value.iter().copied().collect();
+                if !items.iter().any(|i| is_unknown(*i)) {
+                    items.extend(field.iter().copied().filter(|i| is_unknown(*i)));
+                }
+                *field = items;
+            }
+        }
+        if let Some(etanks) = self.etanks {
+            samus.set_etanks(etanks)?;
+        }
+        if let Some(tanks) = self.reserve_tanks {
+            samus.set_reserve_tanks(tanks)?;
+        }
+        if let Some(packs) = self.missile_packs {
+            samus.set_missile_packs(packs)?;
+        }
+        if let Some(packs) = self.super_packs {
+            samus.set_super_packs(packs)?;
+        }
+        if let Some(packs) = self.pb_packs {
+            samus.set_pb_packs(packs)?;
+        }
+        set(&mut samus.hp, &self.hp);
+        set(&mut samus.max_hp, &self.max_hp);
+        set(&mut samus.missiles, &self.missiles);
+        set(&mut samus.max_missiles, &self.max_missiles);
+        set(&mut samus.supers, &self.supers);
+        set(&mut samus.max_supers, &self.max_supers);
+        set(&mut samus.pbs, &self.pbs);
+        set(&mut samus.max_pbs, &self.max_pbs);
+        set_items(
+            &mut samus.equipped_items,
+            &self.equipped_items,
+            Item::is_unknown,
+        );
+        set_items(
+            &mut samus.collected_items,
+            &self.collected_items,
+            Item::is_unknown,
+        );
+        set_items(
+            &mut samus.equipped_beams,
+            &self.equipped_beams,
+            Beam::is_unknown,
+        );
+        set_items(
+            &mut samus.collected_beams,
+            &self.collected_beams,
+            Beam::is_unknown,
+        );
+        set(&mut samus.reserve_hp, &self.reserve_hp);
+        set(&mut samus.max_reserve_hp, &self.max_reserve_hp);
+        set(&mut samus.reserve_mode, &self.reserve_mode);
+        set(&mut samus.x_position, &self.x_position);
+        set(&mut samus.x_subposition, &self.x_subposition);
+        set(&mut samus.y_position, &self.y_position);
+        set(&mut samus.y_subposition, &self.y_subposition);
+        set_items(&mut samus.events, &self.events, Event::is_unknown);
+        if let Some(bosses) = &self.bosses {
+            for (area, bosses) in bosses {
+                samus.bosses.insert(*area, bosses.iter().copied().collect());
+            }
+        }
+        if let Some(defeated) = &self.defeated {
+            for boss in NAMED_BOSSES {
+                samus.set_defeated(boss, defeated.contains(&boss));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> Result<String, Box<dyn Error>> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    pub fn to_json(&self) -> Result<String, Box<dyn Error>> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    // Files ending in .json are read as JSON, everything else as TOML
+    pub fn load(path: &Path) -> Result<Loadout, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)?;
+        if path.extension().map(|e| e == "json").unwrap_or(false) {
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            Ok(toml::from_str(&text)?)
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//! Which item locations have been picked up.
+//!
+//! Every item PLM carries an index into the bitfield at $7ED870, the game
+//! sets that bit when the item is collected and won't spawn it again. This
+//! is separate from the equipment bits in `Samus`, so resetting to a state
+//! needs both to put items back where they were.
+
+use crate::payload::{Payload, PayloadArgs};
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+
+pub const ITEM_BITS: u16 = 0xD870;
+// The game keeps 0x40 bytes of item bits, vanilla uses the first 155
+pub const ITEM_BITS_LEN: usize = 0x40;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Location {
+    // Bit index into the item bitfield, as given to the item PLM
+    pub index: u16,
+    pub name: &'static str,
+    pub area: Area,
+}
+
+const fn location(index: u16, name: &'static str, area: Area) -> Location {
+    Location { index, name, area }
+}
+
+// The 100 vanilla item locations, in bit order. The gaps are unused bits.
+pub static LOCATIONS: [Location; 100] = [
+    location(0, "Power Bomb (Crateria surface)", Area::Crateria),
+    location(1, "Missile (outside Wrecked Ship bottom)", Area::Crateria),
+    location(2, "Missile (outside Wrecked Ship top)", Area::Crateria),
+    location(3, "Missile (outside Wrecked Ship middle)", Area::Crateria),
+    location(4, "Missile (Crateria moat)", Area::Crateria),
+    location(5, "Energy Tank (Gauntlet)", Area::Crateria),
+    location(6, "Missile (Crateria bottom)", Area::Crateria),
+    location(7, "Bombs", Area::Crateria),
+    location(8, "Energy Tank (Terminator)", Area::Crateria),
+    location(9, "Missile (Crateria gauntlet right)", Area::Crateria),
+    location(10, "Missile (Crateria gauntlet left)", Area::Crateria),
+    location(11, "Super Missile (Crateria)", Area::Crateria),
+    location(12, "Missile (Crateria middle)", Area::Crateria),
+    location(13, "Power Bomb (green Brinstar bottom)", Area::Brinstar),
+    location(14, "Super Missile (pink Brinstar)", Area::Brinstar),
+    location(
+        15,
+        "Missile (green Brinstar below super missile)",
+        Area::Brinstar,
+    ),
+    location(16, "Super Missile (green Brinstar top)", Area::Brinstar),
+    location(17, "Reserve Tank (Brinstar)", Area::Brinstar),
+    location(
+        18,
+        "Missile (green Brinstar behind missile)",
+        Area::Brinstar,
+    ),
+    location(
+        19,
+        "Missile (green Brinstar behind reserve tank)",
+        Area::Brinstar,
+    ),
+    location(20, "Missile (pink Brinstar top)", Area::Brinstar),
+    location(21, "Missile (pink Brinstar bottom)", Area::Brinstar),
+    location(22, "Charge Beam", Area::Brinstar),
+    location(23, "Power Bomb (pink Brinstar)", Area::Brinstar),
+    location(24, "Missile (green Brinstar pipe)", Area::Brinstar),
+    location(25, "Morph Ball (Brinstar)", Area::Brinstar),
+    location(26, "Power Bomb (blue Brinstar)", Area::Brinstar),
+    location(27, "Missile (blue Brinstar middle)", Area::Brinstar),
+    location(28, "Energy Tank (Brinstar ceiling)", Area::Brinstar),
+    location(29, "Energy Tank (Etecoons)", Area::Brinstar),
+    location(30, "Super Missile (green Brinstar bottom)", Area::Brinstar),
+    location(31, "Energy Tank (Waterway)", Area::Brinstar),
+    location(32, "Missile (blue Brinstar bottom)", Area::Brinstar),
+    location(33, "Energy Tank (Brinstar gate)", Area::Brinstar),
+    location(34, "Missile (blue Brinstar top)", Area::Brinstar),
+    location(35, "Missile (blue Brinstar behind missile)", Area::Brinstar),
+    location(36, "X-Ray Scope", Area::Brinstar),
+    location(
+        37,
+        "Power Bomb (red Brinstar sidehopper room)",
+        Area::Brinstar,
+    ),
+    location(38, "Power Bomb (red Brinstar spike room)", Area::Brinstar),
+    location(39, "Missile (red Brinstar spike room)", Area::Brinstar),
+    location(40, "Spazer", Area::Brinstar),
+    location(41, "Energy Tank (Kraid)", Area::Brinstar),
+    location(42, "Missile (Kraid)", Area::Brinstar),
+    location(43, "Varia Suit", Area::Brinstar),
+    location(44, "Missile (lava room)", Area::Norfair),
+    location(45, "Ice Beam", Area::Norfair),
+    location(46, "Missile (below Ice Beam)", Area::Norfair),
+    location(47, "Energy Tank (Crocomire)", Area::Norfair),
+    location(48, "Hi-Jump Boots", Area::Norfair),
+    location(49, "Missile (above Crocomire)", Area::Norfair),
+    location(50, "Missile (Hi-Jump Boots)", Area::Norfair),
+    location(51, "Energy Tank (Hi-Jump Boots)", Area::Norfair),
+    location(52, "Power Bomb (Crocomire)", Area::Norfair),
+    location(53, "Missile (below Crocomire)", Area::Norfair),
+    location(54, "Missile (Grapple Beam)", Area::Norfair),
+    location(55, "Grapple Beam", Area::Norfair),
+    location(56, "Reserve Tank (Norfair)", Area::Norfair),
+    location(57, "Missile (Norfair reserve tank)", Area::Norfair),
+    location(58, "Missile (bubble Norfair green door)", Area::Norfair),
+    location(59, "Missile (bubble Norfair)", Area::Norfair),
+    location(60, "Missile (Speed Booster)", Area::Norfair),
+    location(61, "Speed Booster", Area::Norfair),
+    location(62, "Missile (Wave Beam)", Area::Norfair),
+    location(63, "Wave Beam", Area::Norfair),
+    location(64, "Missile (Gold Torizo)", Area::Norfair),
+    location(65, "Super Missile (Gold Torizo)", Area::Norfair),
+    location(66, "Missile (Mickey Mouse room)", Area::Norfair),
+    location(
+        67,
+        "Missile (lower Norfair above fire flea room)",
+        Area::Norfair,
+    ),
+    location(
+        68,
+        "Power Bomb (lower Norfair above fire flea room)",
+        Area::Norfair,
+    ),
+    location(69, "Power Bomb (Power Bombs of shame)", Area::Norfair),
+    location(70, "Missile (lower Norfair near Wave Beam)", Area::Norfair),
+    location(71, "Energy Tank (Ridley)", Area::Norfair),
+    location(72, "Screw Attack", Area::Norfair),
+    location(73, "Energy Tank (Firefleas)", Area::Norfair),
+    location(128, "Missile (Wrecked Ship middle)", Area::WreckedShip),
+    location(129, "Reserve Tank (Wrecked Ship)", Area::WreckedShip),
+    location(130, "Missile (Gravity Suit)", Area::WreckedShip),
+    location(131, "Missile (Wrecked Ship top)", Area::WreckedShip),
+    location(132, "Energy Tank (Wrecked Ship)", Area::WreckedShip),
+    location(133, "Super Missile (Wrecked Ship left)", Area::WreckedShip),
+    location(134, "Super Missile (Wrecked Ship right)", Area::WreckedShip),
+    location(135, "Gravity Suit", Area::WreckedShip),
+    location(136, "Missile (green Maridia shinespark)", Area::Maridia),
+    location(137, "Super Missile (green Maridia)", Area::Maridia),
+    location(138, "Energy Tank (Mama turtle)", Area::Maridia),
+    location(139, "Missile (green Maridia tatori)", Area::Maridia),
+    location(140, "Super Missile (yellow Maridia)", Area::Maridia),
+    location(141, "Missile (yellow Maridia super missile)", Area::Maridia),
+    location(142, "Missile (yellow Maridia false wall)", Area::Maridia),
+    location(143, "Plasma Beam", Area::Maridia),
+    location(144, "Missile (left Maridia sand pit room)", Area::Maridia),
+    location(145, "Reserve Tank (Maridia)", Area::Maridia),
+    location(146, "Missile (right Maridia sand pit room)", Area::Maridia),
+    location(
+        147,
+        "Power Bomb (right Maridia sand pit room)",
+        Area::Maridia,
+    ),
+    location(148, "Missile (pink Maridia)", Area::Maridia),
+    location(149, "Super Missile (pink Maridia)", Area::Maridia),
+    location(150, "Spring Ball", Area::Maridia),
+    location(151, "Missile (Draygon)", Area::Maridia),
+    location(152, "Energy Tank (Botwoon)", Area::Maridia),
+    location(154, "Space Jump", Area::Maridia),
+];
+
+// Find a location by bit index or by name, ignoring case
+pub fn find(name: &str) -> Option<&'static Location> {
+    if let Ok(index) = name.parse::<u16>() {
+        return LOCATIONS.iter().find(|l| l.index == index);
+    }
+    LOCATIONS.iter().find(|l| l.name.eq_ignore_ascii_case(name))
+}
+
+// Address and mask of a location's bit
+fn bit(index: u16) -> (u32, u8) {
+    (
+        0x7E_0000 + ITEM_BITS as u32 + (index / 8) as u32,
+        1 << (index % 8),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemLocations {
+    bits: [u8; ITEM_BITS_LEN],
+}
+
+impl ItemLocations {
+    pub fn is_collected(&self, index: u16) -> bool {
+        self.bits[index as usize / 8] & (1 << (index % 8)) != 0
+    }
+
+    pub fn set_collected(&mut self, index: u16, collected: bool) {
+        let byte = &mut self.bits[index as usize / 8];
+        if collected {
+            *byte |= 1 << (index % 8);
+        } else {
+            *byte &= !(1 << (index % 8));
+        }
+    }
+
+    pub fn collected(&self) -> impl Iterator<Item = &'static Location> + '_ {
+        LOCATIONS.iter().filter(|l| self.is_collected(l.index))
+    }
+
+    // Writes the whole bitfield back, unused bits included
+    pub fn overwrite_asm(&self) -> Vec<u8> {
+        let mut r = Vec::new();
+        for (i, word) in self.bits.chunks(2).enumerate() {
+            r.extend_from_slice(&lda_immediate_u16(u16::from_le_bytes([word[0], word[1]])));
+            r.extend_from_slice(&sta_long(0x7E_0000 + ITEM_BITS as u32 + 2 * i as u32));
+        }
+        r
+    }
+}
+
+pub fn get_item_locations(client: &mut SyncClient) -> Result<ItemLocations, Box<dyn Error>> {
+    let data = client.get_address(WRAM + ITEM_BITS as u32, ITEM_BITS_LEN)?;
+    let mut bits = [0; ITEM_BITS_LEN];
+    bits.copy_from_slice(&data[..ITEM_BITS_LEN]);
+    Ok(ItemLocations { bits })
+}
+
+// Set or clear one location's bit, leaving the others alone
+pub fn mark_asm(index: u16, collected: bool) -> Vec<u8> {
+    let (address, mask) = bit(index);
+    let mut r = Vec::new();
+    // sep #$20
+    r.push(0xe2);
+    r.push(0x20);
+    r.extend_from_slice(&lda_long(address));
+    if collected {
+        r.extend_from_slice(&ora_immediate_u8(mask));
+    } else {
+        r.extend_from_slice(&and_immediate_u8(!mask));
+    }
+    r.extend_from_slice(&sta_long(address));
+    // rep #$20
+    r.push(0xc2);
+    r.push(0x20);
+    r
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LocationsArgs {
+    #[clap(subcommand)]
+    action: LocationsAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum LocationsAction {
+    /// List the item locations and whether they were picked up
+    List {
+        /// Only list collected locations
+        #[clap(long)]
+        collected: bool,
+    },
+    /// Mark locations as picked up, by name or bit index
+    Mark { locations: Vec<String> },
+    /// Mark locations as not picked up so the items spawn again
+    Unmark { locations: Vec<String> },
+}
+
+fn find_all(names: &[String]) -> Result<Vec<&'static Location>, Box<dyn Error>> {
+    names
+        .iter()
+        .map(|name| find(name).ok_or_else(|| format!("unknown item location {:?}", name).into()))
+        .collect()
+}
+
+fn mark(
+    client: &mut SyncClient,
+    names: &[String],
+    collected: bool,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    let mut payload = Payload::from_args(payload_args);
+    for location in find_all(names)? {
+        payload.push(&mark_asm(location.index, collected));
+    }
+    if payload.is_empty() {
+        return Ok(());
+    }
+    payload.run(client)
+}
+
+pub fn run(
+    client: &mut SyncClient,
+    args: LocationsArgs,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    match args.action {
+        LocationsAction::List { collected } => {
+            let locations = get_item_locations(client)?;
+            for location in &LOCATIONS {
+                let is_collected = locations.is_collected(location.index);
+                if collected && !is_collected {
+                    continue;
+                }
+                println!(
+                    "[{}] {:3} {:?}: {}",
+                    if is_collected { "x" } else { " " },
+                    location.index,
+                    location.area,
+                    location.name
+                );
+            }
+        }
+        LocationsAction::Mark { locations } => mark(client, &locations, true, payload_args)?,
+        LocationsAction::Unmark { locations } => mark(client, &locations, false, payload_args)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Bus, MemoryBus};
+
+    #[test]
+    fn overwrite_lands_in_wram() {
+        let mut bus = MemoryBus::new();
+        bus.load(0x7E_0000 + ITEM_BITS as u32, &[0xFF; ITEM_BITS_LEN]);
+        let mut locations = ItemLocations {
+            bits: [0; ITEM_BITS_LEN],
+        };
+        let space_jump = find("Space Jump").unwrap().index;
+        locations.set_collected(space_jump, true);
+        let mut payload = Payload::new();
+        payload.push(&locations.overwrite_asm());
+        payload.run_on(&mut bus);
+        let mut data = [0; ITEM_BITS_LEN];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = bus.read(0x7E_0000 + ITEM_BITS as u32 + i as u32);
+        }
+        assert_eq!(ItemLocations { bits: data }, locations);
+        assert_eq!(bus.read(0x80_0000 + ITEM_BITS as u32), 0);
+    }
+
+    #[test]
+    fn mark_and_unmark() {
+        let mut bus = MemoryBus::new();
+        let mut payload = Payload::new();
+        payload.push(&mark_asm(154, true));
+        payload.push(&mark_asm(0, true));
+        payload.push(&mark_asm(0, false));
+        payload.run_on(&mut bus);
+        assert_eq!(bus.read(0x7E_0000 + ITEM_BITS as u32), 0);
+        assert_eq!(
+            bus.read(0x7E_0000 + ITEM_BITS as u32 + 154 / 8),
+            1 << (154 % 8)
+        );
+    }
+}
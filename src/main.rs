@@ -1,10 +1,36 @@
+pub mod bosses;
+pub mod cpu;
+pub mod cycles;
+pub mod diff;
+pub mod doors;
+pub mod enemies;
+pub mod escape_timer;
+pub mod igt;
+pub mod input;
+pub mod loadout;
+pub mod locations;
+pub mod payload;
+pub mod peephole;
+pub mod physics;
+pub mod pickups;
+pub mod plms;
+pub mod position;
+pub mod rom;
+pub mod room;
+pub mod savestate;
+pub mod shortcuts;
+pub mod teleport;
 pub mod usb2snes;
+pub mod validate;
 
+use clap::{Parser, Subcommand};
 use lazy_static::lazy_static;
+use payload::{Payload, PayloadArgs};
+use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashSet};
 use usb2snes::*;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Samus {
     hp: u16,
     max_hp: u16,
@@ -20,14 +46,16 @@ pub struct Samus {
     collected_beams: HashSet<Beam>,
     reserve_hp: u16,
     max_reserve_hp: u16,
+    reserve_mode: ReserveMode,
     x_position: u16,
     x_subposition: u16,
     y_position: u16,
     y_subposition: u16,
     bosses: BTreeMap<Area, HashSet<Boss>>,
+    events: HashSet<Event>,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 enum SamusField {
     HP,
     MaxHP,
@@ -43,11 +71,13 @@ enum SamusField {
     CollectedBeams,
     ReserveHP,
     MaxReserveHP,
+    ReserveMode,
     XPosition,
     XSubPosition,
     YPosition,
     YSubPosition,
     Bosses,
+    Events,
 }
 
 lazy_static! {
@@ -67,11 +97,13 @@ lazy_static! {
         m.insert(SamusField::CollectedBeams, 0x09A8);
         m.insert(SamusField::ReserveHP, 0x09D6);
         m.insert(SamusField::MaxReserveHP, 0x09D4);
+        m.insert(SamusField::ReserveMode, 0x09C0);
         m.insert(SamusField::XPosition, 0x0AF6);
         m.insert(SamusField::XSubPosition, 0x0AF8);
         m.insert(SamusField::YPosition, 0x0AFA);
         m.insert(SamusField::YSubPosition, 0x0AFC);
         m.insert(SamusField::Bosses, 0xD828);
+        m.insert(SamusField::Events, 0xD820);
         m
     };
 }
@@ -104,35 +136,81 @@ const TOURIAN: u8 = 5;
 const CERES: u8 = 6;
 const DEBUG: u8 = 7;
 
-#[repr(u16)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-enum Item {
-    Varia = VARIA,
-    SpringBall = SPRINGBALL,
-    MorphBall = MORPHBALL,
-    ScrewAttack = SCREWATTACK,
-    Gravity = GRAVITY,
-    HiJumpBoots = HIJUMPBOOTS,
-    SpaceJump = SPACEJUMP,
-    Bombs = BOMBS,
-    SpeedBooster = SPEEDBOOSTER,
-    Grapple = GRAPPLE,
-    XRay = XRAY,
-}
-
-#[repr(u16)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-enum Beam {
-    Wave = WAVE,
-    Ice = ICE,
-    Spazer = SPAZER,
-    Plasma = PLASMA,
-    Charge = CHARGE,
+// Bits we don't know about (custom items in randomizers and hacks) show up
+// as `Unknown` with their bit number so they survive a round trip. That
+// makes the set carry every bit of the raw word, so there's no separate
+// copy of the word to keep in step with it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Item {
+    Varia,
+    SpringBall,
+    MorphBall,
+    ScrewAttack,
+    Gravity,
+    HiJumpBoots,
+    SpaceJump,
+    Bombs,
+    SpeedBooster,
+    Grapple,
+    XRay,
+    Unknown(#[serde(deserialize_with = "unknown_item_bit")] u8),
+}
+
+impl Item {
+    pub fn bit(self) -> u16 {
+        match self {
+            Item::Varia => VARIA,
+            Item::SpringBall => SPRINGBALL,
+            Item::MorphBall => MORPHBALL,
+            Item::ScrewAttack => SCREWATTACK,
+            Item::Gravity => GRAVITY,
+            Item::HiJumpBoots => HIJUMPBOOTS,
+            Item::SpaceJump => SPACEJUMP,
+            Item::Bombs => BOMBS,
+            Item::SpeedBooster => SPEEDBOOSTER,
+            Item::Grapple => GRAPPLE,
+            Item::XRay => XRAY,
+            Item::Unknown(bit) => 1 << bit,
+        }
+    }
+
+    pub fn is_unknown(self) -> bool {
+        matches!(self, Item::Unknown(_))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Beam {
+    Wave,
+    Ice,
+    Spazer,
+    Plasma,
+    Charge,
+    Unknown(#[serde(deserialize_with = "unknown_beam_bit")] u8),
+}
+
+impl Beam {
+    pub fn bit(self) -> u16 {
+        match self {
+            Beam::Wave => WAVE,
+            Beam::Ice => ICE,
+            Beam::Spazer => SPAZER,
+            Beam::Plasma => PLASMA,
+            Beam::Charge => CHARGE,
+            Beam::Unknown(bit) => 1 << bit,
+        }
+    }
+
+    pub fn is_unknown(self) -> bool {
+        matches!(self, Beam::Unknown(_))
+    }
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-enum Area {
+#[derive(
+    clap::ArgEnum, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum Area {
     Crateria = CRATERIA,
     Brinstar = BRINSTAR,
     Norfair = NORFAIR,
@@ -147,88 +225,252 @@ const MAINBOSS: u8 = 1;
 const MINIBOSS: u8 = 2;
 const TORIZO: u8 = 4;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 #[repr(u8)]
-enum Boss {
+#[allow(clippy::enum_variant_names)]
+pub enum Boss {
     MainBoss = MAINBOSS,
     MiniBoss = MINIBOSS,
     Torizo = TORIZO,
 }
 
-fn items_to_u16(items: &[&Item]) -> u16 {
-    let mut r = 0u16;
-    for i in items {
-        r |= **i as u16;
-    }
-    r
+const ZEBES_AWAKE: u16 = 1;
+const MOTHER_BRAIN_GLASS_BROKEN: u16 = 2;
+const ZEBES_TIMEBOMB_SET: u16 = 4;
+const CRITTERS_ESCAPED: u16 = 8;
+const FIRST_METROID_HALL_CLEARED: u16 = 0x10;
+const FIRST_METROID_SHAFT_CLEARED: u16 = 0x20;
+const SECOND_METROID_HALL_CLEARED: u16 = 0x40;
+const SECOND_METROID_SHAFT_CLEARED: u16 = 0x80;
+const OUTRAN_SPEED_BOOSTER_LAVAQUAKE: u16 = 0x200;
+const LOWER_NORFAIR_ACID_LOWERED: u16 = 0x400;
+const MARIDIA_TUBE_BROKEN: u16 = 0x800;
+const SHAKTOOL_DONE_DIGGING: u16 = 0x1000;
+const TOURIAN_STATUES_CLEARED: u16 = 0x2000;
+
+// Global story flags. Like items, bits without a name (unused in vanilla,
+// or used by hacks) come through as `Unknown`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Event {
+    ZebesAwake,
+    MotherBrainGlassBroken,
+    ZebesTimebombSet,
+    CrittersEscaped,
+    FirstMetroidHallCleared,
+    FirstMetroidShaftCleared,
+    SecondMetroidHallCleared,
+    SecondMetroidShaftCleared,
+    OutranSpeedBoosterLavaquake,
+    LowerNorfairAcidLowered,
+    MaridiaTubeBroken,
+    ShaktoolDoneDigging,
+    TourianStatuesCleared,
+    Unknown(#[serde(deserialize_with = "unknown_event_bit")] u8),
 }
 
-fn u16_to_items(items: u16) -> HashSet<Item> {
-    let mut r = HashSet::new();
-    if items & VARIA == VARIA {
-        r.insert(Item::Varia);
-    }
-    if items & SPRINGBALL == SPRINGBALL {
-        r.insert(Item::SpringBall);
-    }
-    if items & MORPHBALL == MORPHBALL {
-        r.insert(Item::MorphBall);
-    }
-    if items & SCREWATTACK == SCREWATTACK {
-        r.insert(Item::ScrewAttack);
-    }
-    if items & GRAVITY == GRAVITY {
-        r.insert(Item::Gravity);
-    }
-    if items & HIJUMPBOOTS == HIJUMPBOOTS {
-        r.insert(Item::HiJumpBoots);
-    }
-    if items & SPACEJUMP == SPACEJUMP {
-        r.insert(Item::SpaceJump);
+impl Event {
+    pub fn bit(self) -> u16 {
+        match self {
+            Event::ZebesAwake => ZEBES_AWAKE,
+            Event::MotherBrainGlassBroken => MOTHER_BRAIN_GLASS_BROKEN,
+            Event::ZebesTimebombSet => ZEBES_TIMEBOMB_SET,
+            Event::CrittersEscaped => CRITTERS_ESCAPED,
+            Event::FirstMetroidHallCleared => FIRST_METROID_HALL_CLEARED,
+            Event::FirstMetroidShaftCleared => FIRST_METROID_SHAFT_CLEARED,
+            Event::SecondMetroidHallCleared => SECOND_METROID_HALL_CLEARED,
+            Event::SecondMetroidShaftCleared => SECOND_METROID_SHAFT_CLEARED,
+            Event::OutranSpeedBoosterLavaquake => OUTRAN_SPEED_BOOSTER_LAVAQUAKE,
+            Event::LowerNorfairAcidLowered => LOWER_NORFAIR_ACID_LOWERED,
+            Event::MaridiaTubeBroken => MARIDIA_TUBE_BROKEN,
+            Event::ShaktoolDoneDigging => SHAKTOOL_DONE_DIGGING,
+            Event::TourianStatuesCleared => TOURIAN_STATUES_CLEARED,
+            Event::Unknown(bit) => 1 << bit,
+        }
     }
-    if items & BOMBS == BOMBS {
-        r.insert(Item::Bombs);
+
+    pub fn is_unknown(self) -> bool {
+        matches!(self, Event::Unknown(_))
     }
-    if items & SPEEDBOOSTER == SPEEDBOOSTER {
-        r.insert(Item::SpeedBooster);
+}
+
+fn events_to_u16(events: &[&Event]) -> u16 {
+    let mut r = 0u16;
+    for e in events {
+        r |= e.bit();
     }
-    if items & GRAPPLE == GRAPPLE {
-        r.insert(Item::Grapple);
+    r
+}
+
+const EVENTS: [Event; 13] = [
+    Event::ZebesAwake,
+    Event::MotherBrainGlassBroken,
+    Event::ZebesTimebombSet,
+    Event::CrittersEscaped,
+    Event::FirstMetroidHallCleared,
+    Event::FirstMetroidShaftCleared,
+    Event::SecondMetroidHallCleared,
+    Event::SecondMetroidShaftCleared,
+    Event::OutranSpeedBoosterLavaquake,
+    Event::LowerNorfairAcidLowered,
+    Event::MaridiaTubeBroken,
+    Event::ShaktoolDoneDigging,
+    Event::TourianStatuesCleared,
+];
+
+fn u16_to_events(events: u16) -> HashSet<Event> {
+    (0..16)
+        .filter(|bit| events & (1 << bit) != 0)
+        .map(|bit| {
+            EVENTS
+                .iter()
+                .copied()
+                .find(|event| event.bit() == 1 << bit)
+                .unwrap_or(Event::Unknown(bit))
+        })
+        .collect()
+}
+
+const RESERVE_NONE: u16 = 0;
+const RESERVE_AUTO: u16 = 1;
+const RESERVE_MANUAL: u16 = 2;
+
+// What the game does with reserve energy, none until the first reserve
+// tank is picked up
+#[derive(
+    Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum ReserveMode {
+    #[default]
+    None,
+    Auto,
+    Manual,
+    Unknown(u16),
+}
+
+impl From<u16> for ReserveMode {
+    fn from(mode: u16) -> ReserveMode {
+        match mode {
+            RESERVE_NONE => ReserveMode::None,
+            RESERVE_AUTO => ReserveMode::Auto,
+            RESERVE_MANUAL => ReserveMode::Manual,
+            _ => ReserveMode::Unknown(mode),
+        }
     }
-    if items & XRAY == XRAY {
-        r.insert(Item::XRay);
+}
+
+impl From<ReserveMode> for u16 {
+    fn from(mode: ReserveMode) -> u16 {
+        match mode {
+            ReserveMode::None => RESERVE_NONE,
+            ReserveMode::Auto => RESERVE_AUTO,
+            ReserveMode::Manual => RESERVE_MANUAL,
+            ReserveMode::Unknown(mode) => mode,
+        }
     }
+}
 
+fn items_to_u16(items: &[&Item]) -> u16 {
+    let mut r = 0u16;
+    for i in items {
+        r |= i.bit();
+    }
     r
 }
 
+const ITEMS: [Item; 11] = [
+    Item::Varia,
+    Item::SpringBall,
+    Item::MorphBall,
+    Item::ScrewAttack,
+    Item::Gravity,
+    Item::HiJumpBoots,
+    Item::SpaceJump,
+    Item::Bombs,
+    Item::SpeedBooster,
+    Item::Grapple,
+    Item::XRay,
+];
+
+fn u16_to_items(items: u16) -> HashSet<Item> {
+    (0..16)
+        .filter(|bit| items & (1 << bit) != 0)
+        .map(|bit| {
+            ITEMS
+                .iter()
+                .copied()
+                .find(|item| item.bit() == 1 << bit)
+                .unwrap_or(Item::Unknown(bit))
+        })
+        .collect()
+}
+
 fn beams_to_u16(beams: &[&Beam]) -> u16 {
     let mut r = 0u16;
     for b in beams {
-        r |= **b as u16;
+        r |= b.bit();
     }
     r
 }
 
+const BEAMS: [Beam; 5] = [
+    Beam::Wave,
+    Beam::Ice,
+    Beam::Spazer,
+    Beam::Plasma,
+    Beam::Charge,
+];
+
 fn u16_to_beams(beams: u16) -> HashSet<Beam> {
-    let mut r = HashSet::new();
-    if beams & WAVE == WAVE {
-        r.insert(Beam::Wave);
-    }
-    if beams & ICE == ICE {
-        r.insert(Beam::Ice);
-    }
-    if beams & SPAZER == SPAZER {
-        r.insert(Beam::Spazer);
-    }
-    if beams & PLASMA == PLASMA {
-        r.insert(Beam::Plasma);
+    (0..16)
+        .filter(|bit| beams & (1 << bit) != 0)
+        .map(|bit| {
+            BEAMS
+                .iter()
+                .copied()
+                .find(|beam| beam.bit() == 1 << bit)
+                .unwrap_or(Beam::Unknown(bit))
+        })
+        .collect()
+}
+
+// An `Unknown` bit from a file has to fit in the word and can't be one of
+// the bits that already has a name, or it would come back as that name
+fn unknown_bit<'de, D, T>(
+    deserializer: D,
+    known: &[T],
+    bit_of: fn(T) -> u16,
+) -> Result<u8, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Copy + std::fmt::Debug,
+{
+    use serde::de::Error;
+    let bit = u8::deserialize(deserializer)?;
+    if bit >= 16 {
+        return Err(D::Error::custom(format!(
+            "unknown bit {} is out of range, it has to be 0 to 15",
+            bit
+        )));
     }
-    if beams & CHARGE == CHARGE {
-        r.insert(Beam::Charge);
+    if let Some(named) = known.iter().find(|k| bit_of(**k) == 1 << bit) {
+        return Err(D::Error::custom(format!(
+            "bit {} is {:?}, use the name instead",
+            bit, named
+        )));
     }
+    Ok(bit)
+}
 
-    r
+fn unknown_item_bit<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    unknown_bit(deserializer, &ITEMS, Item::bit)
+}
+
+fn unknown_beam_bit<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    unknown_bit(deserializer, &BEAMS, Beam::bit)
+}
+
+fn unknown_event_bit<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    unknown_bit(deserializer, &EVENTS, Event::bit)
 }
 
 fn u8_to_bosses(bosses: u8) -> HashSet<Boss> {
@@ -315,41 +557,237 @@ fn area_bosses_to_bytes(areas: &BTreeMap<Area, HashSet<Boss>>) -> Vec<(u16, u8)>
     ret
 }
 
+#[derive(Parser, Debug)]
+#[clap(about = "Super Metroid practice tool for usb2snes")]
+struct Args {
+    #[clap(flatten)]
+    payload: PayloadArgs,
+    /// Wait for normal gameplay before sending edits
+    #[clap(long)]
+    wait: bool,
+    #[clap(flatten)]
+    conditions: room::Conditions,
+    #[clap(subcommand)]
+    action: Option<Action>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Save and load states with the FXPak savestate code
+    Savestate(savestate::SavestateArgs),
+    /// Inspect and edit Samus
+    #[clap(subcommand)]
+    Samus(SamusAction),
+    /// Inspect and edit which item locations were picked up
+    Locations(locations::LocationsArgs),
+    /// Inspect and edit which colored doors were opened
+    Doors(doors::DoorsArgs),
+    /// Read and set the in-game time
+    Igt(igt::IgtArgs),
+    /// Read and change the escape timer
+    EscapeTimer(escape_timer::EscapeTimerArgs),
+    /// Load the game from a save station or elevator
+    Teleport(teleport::TeleportArgs),
+    /// Move Samus around the current room
+    Position(position::PositionArgs),
+    /// Inspect and edit the enemies in the current room
+    Enemies(enemies::EnemiesArgs),
+    /// Inspect and delete the PLMs in the current room
+    Plms(plms::PlmsArgs),
+    /// Inspect and edit Samus' pose and movement
+    Physics(physics::PhysicsArgs),
+    /// Read the controller, or show it live as an input display
+    Input(input::InputArgs),
+    /// Run actions when controller combos are pressed
+    Shortcuts(shortcuts::ShortcutsArgs),
+    /// Read and patch the ROM while the game runs
+    Rom(rom::RomArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum SamusAction {
+    /// Print Samus's current state
+    Show,
+    /// Print Samus's current state as a loadout file
+    Dump {
+        /// Write JSON instead of TOML
+        #[clap(long)]
+        json: bool,
+    },
+    /// Apply a loadout file, fields it leaves out are kept as they are
+    Apply {
+        file: std::path::PathBuf,
+        /// Rewrite every field instead of only the ones that changed
+        #[clap(long)]
+        full: bool,
+        /// Write whole item and beam words instead of flipping changed bits
+        #[clap(long)]
+        no_rmw: bool,
+        /// Repair what can be repaired (clamp values, drop Spazer with
+        /// Plasma, collect equipped items) before applying
+        #[clap(long)]
+        fix: bool,
+        /// Apply even if validation reports errors
+        #[clap(long)]
+        force: bool,
+    },
+    /// Mark bosses as defeated
+    Defeat {
+        #[clap(arg_enum, required = true)]
+        bosses: Vec<bosses::NamedBoss>,
+    },
+    /// Mark bosses as not defeated so they can be fought again
+    Revive {
+        #[clap(arg_enum, required = true)]
+        bosses: Vec<bosses::NamedBoss>,
+    },
+}
+
+fn samus_command(
+    client: &mut SyncClient,
+    action: SamusAction,
+    payload_args: PayloadArgs,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut samus = get_samus(client)?;
+    match action {
+        SamusAction::Show => {
+            println!("{:#?}", samus);
+            println!(
+                "{} E-tanks, {} reserve tanks, {} missile packs, {} super packs, {} PB packs",
+                samus.etanks(),
+                samus.reserve_tanks(),
+                samus.missile_packs(),
+                samus.super_packs(),
+                samus.pb_packs()
+            );
+            println!("defeated: {:?}", samus.defeated());
+            println!("IGT: {}", igt::get_igt(client)?);
+            println!("location: {}", room::get_location(client)?);
+        }
+        SamusAction::Dump { json } => {
+            let loadout = loadout::Loadout::from(&samus);
+            if json {
+                println!("{}", loadout.to_json()?);
+            } else {
+                print!("{}", loadout.to_toml()?);
+            }
+        }
+        SamusAction::Apply {
+            file,
+            full,
+            no_rmw,
+            fix,
+            force,
+        } => {
+            let before = samus.clone();
+            let loadout = loadout::Loadout::load(&file)?;
+            loadout.apply(&mut samus)?;
+            let violations = if fix {
+                samus.fix(validate::Fixes::all())
+            } else {
+                samus.validate()
+            };
+            for violation in &violations {
+                eprintln!("{}", violation);
+            }
+            let errors = violations
+                .iter()
+                .any(|v| v.severity == validate::Severity::Error);
+            if errors && !force {
+                return Err("refusing to apply a loadout with errors, use --fix or --force".into());
+            }
+            let mut payload = Payload::from_args(payload_args);
+            if full {
+                payload.push(&samus_overwrite_asm(&samus));
+            } else {
+                let diff = diff::SamusDiff::new(&before, &samus);
+                if diff.is_empty() {
+                    return Ok(());
+                }
+                payload.push(&diff.to_asm(!no_rmw));
+            }
+            payload.run(client)?;
+        }
+        SamusAction::Defeat { bosses } => set_defeated(client, samus, &bosses, true, payload_args)?,
+        SamusAction::Revive { bosses } => {
+            set_defeated(client, samus, &bosses, false, payload_args)?
+        }
+    }
+    Ok(())
+}
+
+fn set_defeated(
+    client: &mut SyncClient,
+    mut samus: Samus,
+    bosses: &[bosses::NamedBoss],
+    defeated: bool,
+    payload_args: PayloadArgs,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let before = samus.clone();
+    for boss in bosses {
+        samus.set_defeated(*boss, defeated);
+    }
+    let diff = diff::SamusDiff::new(&before, &samus);
+    if diff.is_empty() {
+        return Ok(());
+    }
+    let mut payload = Payload::from_args(payload_args);
+    payload.push(&diff.to_asm(true));
+    payload.run(client)
+}
+
+// A channel that gets a message on enter, EOF or ctrl-c, whichever is first.
+// For commands that have something to undo before exiting.
+pub fn enter_or_ctrl_c(
+) -> std::result::Result<std::sync::mpsc::Receiver<()>, Box<dyn std::error::Error>> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let ctrl_c = tx.clone();
+    ctrlc::set_handler(move || {
+        let _ = ctrl_c.send(());
+    })?;
+    std::thread::spawn(move || {
+        let _ = std::io::stdin().read_line(&mut String::new());
+        let _ = tx.send(());
+    });
+    Ok(rx)
+}
+
 fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
     let mut client = crate::usb2snes::SyncClient::connect()?;
     client.set_name("goofgenie")?;
     let device_list = client.list_device()?.to_vec();
-    println!("{:#?}", device_list);
     if device_list.len() == 1 {
         client.attach(&device_list[0])?;
     }
 
+    args.conditions.check(&mut client)?;
+
+    // Subcommands keep stdout to themselves so it can be redirected
+    if let Some(action) = args.action {
+        return match action {
+            Action::Savestate(args) => savestate::run(&mut client, args),
+            Action::Samus(action) => samus_command(&mut client, action, args.payload),
+            Action::Locations(action) => locations::run(&mut client, action, args.payload),
+            Action::Doors(action) => doors::run(&mut client, action, args.payload),
+            Action::Igt(action) => igt::run(&mut client, action, args.payload),
+            Action::EscapeTimer(action) => escape_timer::run(&mut client, action, args.payload),
+            Action::Teleport(action) => teleport::run(&mut client, action, args.payload),
+            Action::Position(action) => position::run(&mut client, action, args.payload),
+            Action::Enemies(action) => enemies::run(&mut client, action, args.payload),
+            Action::Plms(action) => plms::run(&mut client, action, args.payload),
+            Action::Physics(action) => physics::run(&mut client, action, args.payload),
+            Action::Input(action) => input::run(&mut client, action),
+            Action::Shortcuts(action) => shortcuts::run(&mut client, action, args.payload),
+            Action::Rom(action) => rom::run(&mut client, action),
+        };
+    }
+
+    println!("{:#?}", device_list);
     println!("{:#?}", client.info());
 
-    let cmd = client.get_cmd()?;
-    //println!("{:02x?}", cmd);
-    // preamble corresponds to:
-    // php
-    // rep #$30
-    // pha
-    // phx
-    // phy
-    // phb
-    //
-    // And postamble corresponds to:
-    // plb
-    // stz $2c00 ; disable this command
-    // ply
-    // plx
-    // pla
-    // plp
-    // jmp ($ffea) ; run the normal nmi code
-    //
-    let preamble: [u8; 7] = [0x08, 0xc2, 0x30, 0x48, 0xda, 0x5a, 0x8b];
-    let postamble: [u8; 11] = [
-        0xab, 0x9c, 0x00, 0x2c, 0x7a, 0xfa, 0x68, 0x28, 0x6c, 0xea, 0xff,
-    ];
     let mut samus = get_samus(&mut client)?;
+    let before = samus.clone();
     println!("{:#?}", samus);
     // Example samus edit:
     //samus.collected_items.insert(Item::Varia);
@@ -396,29 +834,32 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
     samus.bosses.insert(Area::Brinstar, all_bosses.clone());
     samus.bosses.insert(Area::Maridia, all_bosses.clone());
     samus.bosses.insert(Area::WreckedShip, all_bosses.clone());
-    let mut data = Vec::new();
-    data.extend_from_slice(&preamble);
-    //data.extend_from_slice(&move_left_half_tile());
-    //data.extend_from_slice(&enable_hyperbeam());
-    //data.extend_from_slice(&disable_hyperbeam());
-    data.extend_from_slice(&samus_overwrite_asm(&samus));
-    //data.extend_from_slice(&add_one_minute_to_timer());
-    //data.extend_from_slice(&max_kill_count());
-    //data.extend_from_slice(&delete_plms());
-    //data.extend_from_slice(&spike_suit_asm());
-    //data.extend_from_slice(&blue_suit_asm());
-    //data.extend_from_slice(&g_mode_asm());
-    data.extend_from_slice(&postamble);
-    client.put_cmd(&data)?;
-    loop {
-        let header = client.get_cmd_header_byte()?;
-        if header == 0 {
-            break;
-        }
+    for violation in samus.validate() {
+        println!("{}", violation);
+    }
+    let mut payload = Payload::from_args(args.payload);
+    //payload.push(&move_left_half_tile());
+    //payload.push(&enable_hyperbeam());
+    //payload.push(&disable_hyperbeam());
+    payload.push(&diff::SamusDiff::new(&before, &samus).to_asm(true));
+    //payload.push(&samus_overwrite_asm(&samus));
+    //payload.push(&add_one_minute_to_timer());
+    //payload.push(&max_kill_count());
+    //plms::delete_plms().iter().for_each(|block| payload.push(block));
+    //payload.push(&spike_suit_asm());
+    //payload.push(&blue_suit_asm());
+    //payload.push(&g_mode_asm());
+    let estimate = payload.estimate();
+    println!(
+        "payload estimate: {} cycles, {} master cycles ({:.1} scanlines)",
+        estimate.cycles,
+        estimate.master_cycles,
+        estimate.scanlines()
+    );
+    if args.wait {
+        wait_until_gameplay(&mut client, std::time::Duration::from_secs(60))?;
     }
-    client.put_cmd(&cmd)?;
-    let new_cmd = client.get_cmd()?;
-    assert_eq!(cmd, new_cmd);
+    payload.run(&mut client)?;
 
     //let samus = get_samus(&mut client)?;
     //println!("{:#?}", samus);
@@ -441,6 +882,28 @@ pub fn lda_addr(address: u16) -> [u8; 3] {
     [0xad, bytes[0], bytes[1]]
 }
 
+pub fn lda_long(address: u32) -> [u8; 4] {
+    let bytes = address.to_le_bytes();
+    [0xaf, bytes[0], bytes[1], bytes[2]]
+}
+
+pub fn ldx_addr(address: u16) -> [u8; 3] {
+    let bytes = u16_to_le(address);
+    [0xae, bytes[0], bytes[1]]
+}
+
+pub fn txa() -> [u8; 1] {
+    [0x8a]
+}
+
+pub fn tax() -> [u8; 1] {
+    [0xaa]
+}
+
+pub fn lsr() -> [u8; 1] {
+    [0x4a]
+}
+
 pub fn inc() -> [u8; 1] {
     [0x1A]
 }
@@ -486,12 +949,109 @@ pub fn sta_long(address: u32) -> [u8; 4] {
     [0x8f, bytes[0], bytes[1], bytes[2]]
 }
 
+pub fn sta_long_x(address: u32) -> [u8; 4] {
+    let bytes = address.to_le_bytes();
+    [0x9f, bytes[0], bytes[1], bytes[2]]
+}
+
+// Payloads run with the data bank at $80, which only mirrors WRAM below
+// $2000. Anything above that has to be addressed in bank $7E.
+pub fn lda_wram(address: u16) -> Vec<u8> {
+    if address < 0x2000 {
+        lda_addr(address).to_vec()
+    } else {
+        lda_long(0x7E_0000 + address as u32).to_vec()
+    }
+}
+
+pub fn sta_wram(address: u16) -> Vec<u8> {
+    if address < 0x2000 {
+        sta_absolute(address).to_vec()
+    } else {
+        sta_long(0x7E_0000 + address as u32).to_vec()
+    }
+}
+
 pub fn stz_absolute(address: u16) -> [u8; 3] {
     let bytes = address.to_le_bytes();
     [0x9c, bytes[0], bytes[1]]
 }
 
-fn get_u16(
+pub fn and_immediate_u16(data: u16) -> [u8; 3] {
+    let bytes = data.to_le_bytes();
+    [0x29, bytes[0], bytes[1]]
+}
+
+pub fn and_immediate_u8(data: u8) -> [u8; 2] {
+    [0x29, data]
+}
+
+pub fn ora_immediate_u16(data: u16) -> [u8; 3] {
+    let bytes = data.to_le_bytes();
+    [0x09, bytes[0], bytes[1]]
+}
+
+pub fn ora_immediate_u8(data: u8) -> [u8; 2] {
+    [0x09, data]
+}
+
+pub fn cmp_immediate_u16(data: u16) -> [u8; 3] {
+    let bytes = data.to_le_bytes();
+    [0xc9, bytes[0], bytes[1]]
+}
+
+pub fn cmp_immediate_u8(data: u8) -> [u8; 2] {
+    [0xc9, data]
+}
+
+pub fn sbc_immediate_u8(data: u8) -> [u8; 2] {
+    [0xe9, data]
+}
+
+pub fn sbc_immediate_u16(data: u16) -> [u8; 3] {
+    let bytes = data.to_le_bytes();
+    [0xe9, bytes[0], bytes[1]]
+}
+
+pub fn clc() -> [u8; 1] {
+    [0x18]
+}
+
+pub fn sec() -> [u8; 1] {
+    [0x38]
+}
+
+pub fn beq(offset: i8) -> [u8; 2] {
+    [0xf0, offset as u8]
+}
+
+pub fn bcc(offset: i8) -> [u8; 2] {
+    [0x90, offset as u8]
+}
+
+pub fn bcs(offset: i8) -> [u8; 2] {
+    [0xb0, offset as u8]
+}
+
+pub fn jmp_absolute(address: u16) -> [u8; 3] {
+    let bytes = address.to_le_bytes();
+    [0x4c, bytes[0], bytes[1]]
+}
+
+pub fn nop() -> [u8; 1] {
+    [0xea]
+}
+
+pub fn bra(offset: i8) -> [u8; 2] {
+    [0x80, offset as u8]
+}
+
+pub fn jml(address: u32) -> [u8; 4] {
+    let bytes = address.to_le_bytes();
+    [0x5c, bytes[0], bytes[1], bytes[2]]
+}
+
+pub fn get_u16(
     client: &mut SyncClient,
     address: u32,
 ) -> std::result::Result<u16, Box<dyn std::error::Error>> {
@@ -499,11 +1059,33 @@ fn get_u16(
     Ok(((response[1] as u16) << 8) + response[0] as u16)
 }
 
+// Poll the game state until Samus is in normal gameplay
+fn wait_until_gameplay(
+    client: &mut SyncClient,
+    timeout: std::time::Duration,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let start = std::time::Instant::now();
+    loop {
+        let state = room::get_game_state(client)?;
+        if state == room::GameState::Gameplay {
+            return Ok(());
+        }
+        if start.elapsed() > timeout {
+            return Err(
+                format!("timed out waiting for gameplay, game state is {:?}", state).into(),
+            );
+        }
+        std::thread::sleep(std::time::Duration::from_millis(16));
+    }
+}
+
 fn get_wram_addr(field: SamusField) -> u32 {
     *SAMUS_ADDR_MAP.get(&field).unwrap() as u32 + WRAM
 }
 
-fn get_samus(client: &mut SyncClient) -> std::result::Result<Samus, Box<dyn std::error::Error>> {
+pub fn get_samus(
+    client: &mut SyncClient,
+) -> std::result::Result<Samus, Box<dyn std::error::Error>> {
     let hp = get_u16(client, get_wram_addr(SamusField::HP))?;
     let max_hp = get_u16(client, get_wram_addr(SamusField::MaxHP))?;
     let missiles = get_u16(client, get_wram_addr(SamusField::Missiles))?;
@@ -518,6 +1100,7 @@ fn get_samus(client: &mut SyncClient) -> std::result::Result<Samus, Box<dyn std:
     let collected_beams = u16_to_beams(get_u16(client, get_wram_addr(SamusField::CollectedBeams))?);
     let reserve_hp = get_u16(client, get_wram_addr(SamusField::ReserveHP))?;
     let max_reserve_hp = get_u16(client, get_wram_addr(SamusField::MaxReserveHP))?;
+    let reserve_mode = get_u16(client, get_wram_addr(SamusField::ReserveMode))?.into();
     let x_position = get_u16(client, get_wram_addr(SamusField::XPosition))?;
     let y_position = get_u16(client, get_wram_addr(SamusField::YPosition))?;
     let x_subposition = get_u16(client, get_wram_addr(SamusField::XSubPosition))?;
@@ -531,6 +1114,7 @@ fn get_samus(client: &mut SyncClient) -> std::result::Result<Samus, Box<dyn std:
         ));
     }
     let bosses = to_area_bosses(&bosses);
+    let events = u16_to_events(get_u16(client, get_wram_addr(SamusField::Events))?);
 
     Ok(Samus {
         hp,
@@ -545,6 +1129,7 @@ fn get_samus(client: &mut SyncClient) -> std::result::Result<Samus, Box<dyn std:
         collected_items,
         reserve_hp,
         max_reserve_hp,
+        reserve_mode,
         collected_beams,
         equipped_beams,
         x_position,
@@ -552,6 +1137,7 @@ fn get_samus(client: &mut SyncClient) -> std::result::Result<Samus, Box<dyn std:
         x_subposition,
         y_subposition,
         bosses,
+        events,
     })
 }
 
@@ -640,6 +1226,10 @@ pub fn samus_overwrite_asm(samus: &Samus) -> Vec<u8> {
     r.extend_from_slice(&sta_absolute(
         *SAMUS_ADDR_MAP.get(&SamusField::MaxReserveHP).unwrap(),
     ));
+    r.extend_from_slice(&lda_immediate_u16(samus.reserve_mode.into()));
+    r.extend_from_slice(&sta_absolute(
+        *SAMUS_ADDR_MAP.get(&SamusField::ReserveMode).unwrap(),
+    ));
     r.extend_from_slice(&lda_immediate_u16(samus.x_position));
     r.extend_from_slice(&sta_absolute(
         *SAMUS_ADDR_MAP.get(&SamusField::XPosition).unwrap(),
@@ -657,6 +1247,11 @@ pub fn samus_overwrite_asm(samus: &Samus) -> Vec<u8> {
         *SAMUS_ADDR_MAP.get(&SamusField::YSubPosition).unwrap(),
     ));
 
+    r.extend_from_slice(&lda_immediate_u16(events_to_u16(
+        &samus.events.iter().collect::<Vec<_>>(),
+    )));
+    r.extend_from_slice(&sta_wram(*SAMUS_ADDR_MAP.get(&SamusField::Events).unwrap()));
+
     // Boss event flags
     // sep #$20
     r.push(0xe2);
@@ -679,7 +1274,7 @@ pub fn blue_suit_asm() -> Vec<u8> {
     r.push(0xe2);
     r.push(0x20);
     r.extend_from_slice(&lda_immediate_u8(4));
-    r.extend_from_slice(&sta_absolute(0x0b3f));
+    r.extend_from_slice(&sta_absolute(physics::SPEED_BOOST_LEVEL));
     // rep #$20
     r.push(0xc2);
     r.push(0x20);
@@ -693,7 +1288,7 @@ pub fn spike_suit_asm() -> Vec<u8> {
     r.push(0xe2);
     r.push(0x20);
     r.extend_from_slice(&lda_immediate_u8(1));
-    r.extend_from_slice(&sta_absolute(0x0a68));
+    r.extend_from_slice(&sta_absolute(physics::SHINESPARK_TIMER));
     // rep #$20
     r.push(0xc2);
     r.push(0x20);
@@ -736,34 +1331,104 @@ pub fn disable_hyperbeam() -> Vec<u8> {
 }
 
 pub fn add_one_minute_to_timer() -> Vec<u8> {
-    const TIMER_MINUTES: u16 = 0x0947;
-    let mut r = Vec::new();
-    // sep #$20
-    r.push(0xe2);
-    r.push(0x20);
-    r.extend_from_slice(&lda_addr(TIMER_MINUTES));
-    r.extend_from_slice(&sed());
-    r.extend_from_slice(&adc_immediate_u8(1));
-    r.extend_from_slice(&cld());
-    r.extend_from_slice(&sta_absolute(TIMER_MINUTES));
-    // rep #$20
-    r.push(0xc2);
-    r.push(0x20);
-
-    r
+    escape_timer::EscapeTimer {
+        minutes: 1,
+        seconds: 0,
+        centiseconds: 0,
+    }
+    .add_asm()
 }
 
+// Half a block left, relative to wherever Samus is when it runs. Use
+// `Samus::move_by` for anything that needs a bounds check.
 pub fn move_left_half_tile() -> Vec<u8> {
+    let x = *SAMUS_ADDR_MAP.get(&SamusField::XPosition).unwrap();
     let mut r = Vec::new();
-    r.extend_from_slice(&lda_immediate_u8(1));
-    r.extend_from_slice(&adc_absolute(0x0AF6));
-    r.extend_from_slice(&sta_absolute(0x0AF6));
+    r.extend_from_slice(&lda_addr(x));
+    r.extend_from_slice(&sec());
+    r.extend_from_slice(&sbc_immediate_u16((position::BLOCK_SIZE / 2) as u16));
+    r.extend_from_slice(&sta_absolute(x));
     r
 }
 
+// The NMI hook savestate2snes installs. Disassembled:
+// php
+// rep #$30
+// pha
+// lda $004218 ; joypad 1
+// sta $fc2006 ; hand the input to the savestate code
+// jml $fc0000 ; FXPak savestate code, it jumps back to $2c10 when done
+// rep #$30
+// pla
+// plp
+// jmp ($ffea)
+// jmp ($ffea)
 pub fn savestate2snes() -> Vec<u8> {
     vec![
         0x08, 0xc2, 0x30, 0x48, 0xaf, 0x18, 0x42, 0x00, 0x8f, 0x06, 0x20, 0xfc, 0x5c, 0x00, 0x00,
         0xfc, 0xc2, 0x30, 0x68, 0x28, 0x6c, 0xea, 0xff, 0x6c, 0xea, 0xff,
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Bus, MemoryBus};
+
+    #[test]
+    fn overwrite_lands_in_wram() {
+        let mut samus = Samus {
+            hp: 299,
+            max_hp: 299,
+            missiles: 5,
+            x_position: 0x80,
+            ..Default::default()
+        };
+        samus.events.insert(Event::ZebesAwake);
+        samus.collected_items.insert(Item::Varia);
+        samus
+            .bosses
+            .insert(Area::Norfair, [Boss::MiniBoss].into_iter().collect());
+        let mut bus = MemoryBus::new();
+        let mut payload = Payload::new();
+        payload.push(&samus_overwrite_asm(&samus));
+        payload.run_on(&mut bus);
+        assert_eq!(bus.read_u16(0x7E_09C2), 299);
+        assert_eq!(bus.read_u16(0x7E_09C6), 5);
+        assert_eq!(bus.read_u16(0x7E_0AF6), 0x80);
+        assert_eq!(bus.read_u16(0x7E_09A4), VARIA);
+        assert_eq!(bus.read_u16(0x7E_D820), ZEBES_AWAKE);
+        assert_eq!(bus.read(0x7E_D828 + area_to_u8(&Area::Norfair) as u32), 2);
+        assert_eq!(bus.read_u16(0x80_D820), 0);
+    }
+
+    #[test]
+    fn every_word_round_trips() {
+        for word in 0..=u16::MAX {
+            let items = u16_to_items(word);
+            assert_eq!(items_to_u16(&items.iter().collect::<Vec<_>>()), word);
+            let beams = u16_to_beams(word);
+            assert_eq!(beams_to_u16(&beams.iter().collect::<Vec<_>>()), word);
+            let events = u16_to_events(word);
+            assert_eq!(events_to_u16(&events.iter().collect::<Vec<_>>()), word);
+        }
+    }
+
+    #[test]
+    fn unknown_bits_are_checked() {
+        assert_eq!(
+            serde_json::from_str::<Item>(r#"{"Unknown":4}"#).unwrap(),
+            Item::Unknown(4)
+        );
+        // Out of range
+        assert!(serde_json::from_str::<Item>(r#"{"Unknown":16}"#).is_err());
+        assert!(serde_json::from_str::<Beam>(r#"{"Unknown":200}"#).is_err());
+        // Varia, Wave and ZebesAwake are all bit 0
+        assert!(serde_json::from_str::<Item>(r#"{"Unknown":0}"#).is_err());
+        assert!(serde_json::from_str::<Beam>(r#"{"Unknown":0}"#).is_err());
+        assert!(serde_json::from_str::<Event>(r#"{"Unknown":0}"#).is_err());
+        assert!(
+            toml::from_str::<loadout::Loadout>("collected_items = [{ Unknown = 16 }]").is_err()
+        );
+    }
+}
@@ -0,0 +1,386 @@
+//! Building and running code through the FXPak NMI hook.
+//!
+//! Effects are pushed as blocks that start and end with 16-bit registers.
+//! The payload wraps them in the preamble/postamble and, if the blocks would
+//! take too long to run inside a single NMI, splits them over several. It can
+//! also check the game state first so edits only land during gameplay.
+
+use crate::cycles::{self, CycleEstimate};
+use crate::peephole;
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+
+// preamble corresponds to:
+// php
+// rep #$30
+// pha
+// phx
+// phy
+// phb
+//
+// And postamble corresponds to:
+// plb
+// stz $2c00 ; disable this command
+// ply
+// plx
+// pla
+// plp
+// jmp ($ffea) ; run the normal nmi code
+//
+pub const PREAMBLE: [u8; 7] = [0x08, 0xc2, 0x30, 0x48, 0xda, 0x5a, 0x8b];
+pub const POSTAMBLE: [u8; 11] = [
+    0xab, 0x9c, 0x00, 0x2c, 0x7a, 0xfa, 0x68, 0x28, 0x6c, 0xea, 0xff,
+];
+// Same as the postamble but leaves the command enabled so it runs again
+// next frame:
+// plb
+// ply
+// plx
+// pla
+// plp
+// jmp ($ffea)
+pub const RETRY_POSTAMBLE: [u8; 8] = [0xab, 0x7a, 0xfa, 0x68, 0x28, 0x6c, 0xea, 0xff];
+// Size of the CMD space
+pub const CMD_SIZE: usize = 512;
+
+// What to do with a payload that arrives outside of normal gameplay
+#[derive(clap::ArgEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Guard {
+    // Always run
+    None,
+    // Drop the edits, leaving a marker so the host can tell
+    Skip,
+    // Keep the command around and try again next frame
+    Retry,
+}
+
+// The global flags every payload is built from
+#[derive(clap::Args, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PayloadArgs {
+    /// Only apply edits while the game is in normal gameplay
+    #[clap(long, arg_enum, default_value = "none")]
+    pub guard: Guard,
+    /// Master cycles a payload can take in one NMI before it's split
+    #[clap(long, default_value_t = cycles::DEFAULT_BUDGET)]
+    pub budget: u32,
+    /// Send blocks as written, without the peephole pass
+    #[clap(long)]
+    pub no_optimize: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Payload {
+    blocks: Vec<Vec<u8>>,
+    budget: u32,
+    optimize: bool,
+    guard: Guard,
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Payload {
+    pub fn new() -> Payload {
+        Payload::with_budget(cycles::DEFAULT_BUDGET)
+    }
+
+    // `budget` is in master cycles
+    pub fn with_budget(budget: u32) -> Payload {
+        Payload {
+            blocks: Vec::new(),
+            budget,
+            optimize: true,
+            guard: Guard::None,
+        }
+    }
+
+    pub fn from_args(args: PayloadArgs) -> Payload {
+        let mut payload = Payload::with_budget(args.budget);
+        payload.set_guard(args.guard);
+        payload.set_optimize(!args.no_optimize);
+        payload
+    }
+
+    pub fn set_guard(&mut self, guard: Guard) {
+        self.guard = guard;
+    }
+
+    // Run the peephole pass over each block before building, on by default
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    pub fn push(&mut self, block: &[u8]) {
+        self.blocks.push(block.to_vec());
+    }
+
+    // The blocks as they will be sent
+    fn blocks(&self) -> Vec<Vec<u8>> {
+        if self.optimize {
+            self.blocks
+                .iter()
+                .map(|block| peephole::optimize(block, false, false))
+                .collect()
+        } else {
+            self.blocks.clone()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    fn overhead(&self) -> CycleEstimate {
+        // The preamble starts with whatever widths the game had, assume the
+        // slower 16-bit case for the postamble.
+        cycles::estimate(&PREAMBLE, true, true)
+            + cycles::estimate(&self.guard_asm(0), false, false)
+            + cycles::estimate(&POSTAMBLE, false, false)
+    }
+
+    fn guard_asm(&self, exit: u16) -> Vec<u8> {
+        match self.guard {
+            Guard::None => vec![],
+            Guard::Skip | Guard::Retry => guard_asm(exit),
+        }
+    }
+
+    // Estimated cost of running everything in a single NMI
+    pub fn estimate(&self) -> CycleEstimate {
+        let mut total = self.overhead();
+        for block in &self.blocks() {
+            total += cycles::estimate(block, false, false);
+        }
+        total
+    }
+
+    // Assemble the blocks into one or more complete CMD programs. Blocks are
+    // never split, so a block that doesn't fit the budget on its own gets a
+    // program to itself and a warning.
+    pub fn build(&self) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        let overhead = self.overhead();
+        let max_code = CMD_SIZE - self.wrap(&[]).len();
+        let mut programs = vec![];
+        let mut code: Vec<u8> = vec![];
+        let mut used = overhead;
+        for block in &self.blocks() {
+            if block.len() > max_code {
+                return Err(format!(
+                    "block of {} bytes does not fit in the CMD space ({} bytes available)",
+                    block.len(),
+                    max_code
+                )
+                .into());
+            }
+            let cost = cycles::estimate(block, false, false);
+            if overhead.master_cycles + cost.master_cycles > self.budget {
+                eprintln!(
+                    "warning: block of {} bytes needs ~{} master cycles ({:.1} scanlines), over the budget of {}",
+                    block.len(),
+                    (overhead + cost).master_cycles,
+                    (overhead + cost).scanlines(),
+                    self.budget
+                );
+            }
+            let over_budget = used.master_cycles + cost.master_cycles > self.budget;
+            let over_size = code.len() + block.len() > max_code;
+            if !code.is_empty() && (over_budget || over_size) {
+                programs.push(self.wrap(&code));
+                code.clear();
+                used = overhead;
+            }
+            code.extend_from_slice(block);
+            used += cost;
+        }
+        if !code.is_empty() {
+            programs.push(self.wrap(&code));
+        }
+        Ok(programs)
+    }
+
+    fn wrap(&self, code: &[u8]) -> Vec<u8> {
+        let cmd = cpu::CMD_ADDR as u16;
+        let guard_len = self.guard_asm(0).len();
+        let postamble = PREAMBLE.len() + guard_len + code.len();
+        // Skipping jumps past the postamble to set the marker and then comes
+        // back, retrying goes to a copy of it that leaves the command enabled
+        let exit = postamble + POSTAMBLE.len();
+        let mut data = Vec::new();
+        data.extend_from_slice(&PREAMBLE);
+        data.extend_from_slice(&self.guard_asm(cmd + exit as u16));
+        data.extend_from_slice(code);
+        data.extend_from_slice(&POSTAMBLE);
+        match self.guard {
+            Guard::None => {}
+            Guard::Skip => {
+                let marker = exit + SKIP_EXIT_LEN;
+                data.extend_from_slice(&skip_exit_asm(cmd + postamble as u16, cmd + marker as u16));
+                data.push(0);
+            }
+            Guard::Retry => data.extend_from_slice(&RETRY_POSTAMBLE),
+        }
+        data
+    }
+
+    // Whether `program` took the skip path, only meaningful right after it ran
+    fn skipped(&self, client: &mut SyncClient, program: &[u8]) -> Result<bool, Box<dyn Error>> {
+        if self.guard != Guard::Skip {
+            return Ok(false);
+        }
+        Ok(client.get_cmd_byte(program.len() as u16 - 1)? != 0)
+    }
+
+    // Run a single block on its own
+    pub fn run_block(
+        client: &mut SyncClient,
+        block: &[u8],
+        args: PayloadArgs,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut payload = Payload::from_args(args);
+        payload.push(block);
+        payload.run(client)
+    }
+
+    // Run the payload, one NMI per program
+    pub fn run(&self, client: &mut SyncClient) -> Result<(), Box<dyn Error>> {
+        let programs = self.build()?;
+        if programs.len() > 1 {
+            println!(
+                "payload split over {} frames to stay within {} master cycles",
+                programs.len(),
+                self.budget
+            );
+        }
+        let skipped = run_programs_until(client, &programs, |client, program| {
+            self.skipped(client, program)
+        })?;
+        match skipped {
+            None => Ok(()),
+            Some(0) => Err("skipped: not in gameplay".into()),
+            Some(i) => Err(format!(
+                "skipped: not in gameplay, after {} of {} frames",
+                i,
+                programs.len()
+            )
+            .into()),
+        }
+    }
+
+    // Run the payload in the interpreter instead, for tests
+    #[cfg(test)]
+    pub fn run_on(&self, bus: &mut cpu::MemoryBus) {
+        for program in self.build().unwrap() {
+            cpu::run_nmi_payload(bus, &program).unwrap();
+        }
+    }
+}
+
+// Run complete programs one after the other, then put the CMD space back the
+// way we found it.
+pub fn run_programs(client: &mut SyncClient, programs: &[Vec<u8>]) -> Result<(), Box<dyn Error>> {
+    run_programs_until(client, programs, |_, _| Ok(false))?;
+    Ok(())
+}
+
+// Same, but stop early once `stop` says so after a program has run. Returns
+// the index of the program it stopped on.
+pub fn run_programs_until(
+    client: &mut SyncClient,
+    programs: &[Vec<u8>],
+    mut stop: impl FnMut(&mut SyncClient, &[u8]) -> Result<bool, Box<dyn Error>>,
+) -> Result<Option<usize>, Box<dyn Error>> {
+    let cmd = client.get_cmd()?;
+    let mut stopped = None;
+    for (i, program) in programs.iter().enumerate() {
+        run_cmd(client, program)?;
+        if stop(client, program)? {
+            stopped = Some(i);
+            break;
+        }
+    }
+    client.put_cmd(&cmd)?;
+    let new_cmd = client.get_cmd()?;
+    assert_eq!(cmd, new_cmd);
+    Ok(stopped)
+}
+
+const SKIP_EXIT_LEN: usize = 12;
+
+// Where a skipped payload goes: set the marker byte, which sits right after
+// this code, and leave through the postamble
+fn skip_exit_asm(postamble: u16, marker: u16) -> Vec<u8> {
+    let mut r = Vec::new();
+    // sep #$20
+    r.push(0xe2);
+    r.push(0x20);
+    r.extend_from_slice(&lda_immediate_u8(1));
+    r.extend_from_slice(&sta_absolute(marker));
+    // rep #$20
+    r.push(0xc2);
+    r.push(0x20);
+    r.extend_from_slice(&jmp_absolute(postamble));
+    r
+}
+
+// Jump to `exit` unless the game is in normal gameplay
+pub fn guard_asm(exit: u16) -> Vec<u8> {
+    let mut r = Vec::new();
+    r.extend_from_slice(&lda_addr(room::GAME_STATE));
+    r.extend_from_slice(&and_immediate_u16(0x00FF));
+    r.extend_from_slice(&cmp_immediate_u16(room::GAMEPLAY));
+    r.extend_from_slice(&beq(3));
+    r.extend_from_slice(&jmp_absolute(exit));
+    r
+}
+
+// Upload a complete program and wait for the NMI hook to run it
+pub fn run_cmd(client: &mut SyncClient, program: &[u8]) -> Result<(), Box<dyn Error>> {
+    client.put_cmd(program)?;
+    loop {
+        let header = client.get_cmd_header_byte()?;
+        if header == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Bus, MemoryBus};
+
+    // Run a guarded edit with the game in `state`, returning the marker byte
+    fn run_skip(state: u16) -> (MemoryBus, u8) {
+        let mut bus = MemoryBus::new();
+        bus.load(0x7E_0000 + room::GAME_STATE as u32, &state.to_le_bytes());
+        let mut payload = Payload::new();
+        payload.set_guard(Guard::Skip);
+        let mut block = lda_immediate_u16(0x1234).to_vec();
+        block.extend_from_slice(&sta_long(0x7E_1000));
+        payload.push(&block);
+        let programs = payload.build().unwrap();
+        assert_eq!(programs.len(), 1);
+        cpu::run_nmi_payload(&mut bus, &programs[0]).unwrap();
+        let marker = bus.read(cpu::CMD_ADDR + programs[0].len() as u32 - 1);
+        (bus, marker)
+    }
+
+    #[test]
+    fn skip_leaves_a_marker() {
+        let (mut bus, marker) = run_skip(room::GAMEPLAY);
+        assert_eq!(marker, 0);
+        assert_eq!(bus.read_u16(0x7E_1000), 0x1234);
+        assert_eq!(bus.read(cpu::CMD_ADDR), 0);
+
+        let (mut bus, marker) = run_skip(0);
+        assert_eq!(marker, 1);
+        assert_eq!(bus.read_u16(0x7E_1000), 0);
+        // Still disabled, so it won't run again next frame
+        assert_eq!(bus.read(cpu::CMD_ADDR), 0);
+    }
+}
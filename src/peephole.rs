@@ -0,0 +1,414 @@
+//! Peephole optimisation for the `lda #imm; sta addr` runs our effects are
+//! made of.
+//!
+//! A run of immediate loads, plain stores and `rep/sep #$20` is turned into
+//! the set of bytes it leaves in WRAM, which is then written back with as few
+//! instructions as we can: adjacent bytes become 16-bit writes, zeros use
+//! `stz` where there is one, and writes sharing a value share a single load.
+//! Runs that touch anything but WRAM, or whose accumulator is still used
+//! afterwards, are left alone.
+
+use crate::cpu::{decode, Mode, Op, FLAG_M, FLAG_X};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Insn {
+    pub op: Op,
+    pub mode: Mode,
+    pub operand: u32,
+    // Register widths the instruction runs with
+    pub m8: bool,
+    pub x8: bool,
+}
+
+impl Insn {
+    pub fn encode(&self) -> Vec<u8> {
+        let opcode = (0..=0xFFu8)
+            .find(|b| decode(*b) == (self.op, self.mode))
+            .expect("no opcode for instruction");
+        let len = self.mode.operand_len(self.m8, self.x8);
+        let mut r = vec![opcode];
+        r.extend_from_slice(&self.operand.to_le_bytes()[..len]);
+        r
+    }
+
+    fn is_mode_switch(&self) -> bool {
+        matches!(self.op, Op::Rep | Op::Sep) && self.operand == FLAG_M as u32
+    }
+}
+
+// Decode straight line code into instructions, tracking register widths
+// through rep/sep.
+pub fn disassemble(code: &[u8], m8: bool, x8: bool) -> Vec<Insn> {
+    let mut r = vec![];
+    let mut m8 = m8;
+    let mut x8 = x8;
+    let mut pc = 0;
+    while pc < code.len() {
+        let (op, mode) = decode(code[pc]);
+        let len = mode.operand_len(m8, x8);
+        let mut operand = 0u32;
+        for (i, b) in code.iter().skip(pc + 1).take(len).enumerate() {
+            operand |= (*b as u32) << (8 * i);
+        }
+        r.push(Insn {
+            op,
+            mode,
+            operand,
+            m8,
+            x8,
+        });
+        match op {
+            Op::Rep => {
+                m8 &= operand as u8 & FLAG_M == 0;
+                x8 &= operand as u8 & FLAG_X == 0;
+            }
+            Op::Sep => {
+                m8 |= operand as u8 & FLAG_M != 0;
+                x8 |= operand as u8 & FLAG_X != 0;
+            }
+            _ => {}
+        }
+        pc += 1 + len;
+    }
+    r
+}
+
+pub fn assemble(insns: &[Insn]) -> Vec<u8> {
+    insns.iter().flat_map(|i| i.encode()).collect()
+}
+
+// Where a store goes. `abs` stores rely on the data bank mirroring low WRAM,
+// so they are folded into their $7E address before grouping to keep two
+// stores to the same byte under one key.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Target {
+    Absolute(u16),
+    Long(u32),
+}
+
+impl Target {
+    fn offset(&self, n: u32) -> Target {
+        match self {
+            Target::Absolute(a) => Target::Absolute(a.wrapping_add(n as u16)),
+            Target::Long(a) => Target::Long(a + n),
+        }
+    }
+
+    fn is_wram(&self) -> bool {
+        match self {
+            Target::Absolute(a) => *a < 0x2000,
+            Target::Long(a) => (0x7E_0000..0x80_0000).contains(a),
+        }
+    }
+
+    fn normalize(self) -> Target {
+        match self {
+            Target::Absolute(a) if a < 0x2000 => Target::Long(0x7E_0000 + a as u32),
+            _ => self,
+        }
+    }
+
+    // The short address for a store of `len` bytes, if it all lands in low
+    // WRAM and can go through the mirror
+    fn low_wram(&self, len: u32) -> Option<u16> {
+        match self {
+            Target::Absolute(a) => Some(*a),
+            Target::Long(a) if *a >= 0x7E_0000 && *a + len <= 0x7E_2000 => {
+                Some((*a - 0x7E_0000) as u16)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn is_simple(insn: &Insn) -> bool {
+    matches!(
+        (insn.op, insn.mode),
+        (Op::Lda, Mode::ImmediateM)
+            | (Op::Sta, Mode::Absolute)
+            | (Op::Sta, Mode::AbsoluteLong)
+            | (Op::Stz, Mode::Absolute)
+    ) || insn.is_mode_switch()
+}
+
+// The bytes a run of simple instructions leaves in memory, or None if it
+// writes somewhere we shouldn't reorder or merge writes to.
+fn run_effect(run: &[Insn]) -> Option<BTreeMap<Target, u8>> {
+    let mut a_lo: Option<u8> = None;
+    let mut a_hi: Option<u8> = None;
+    let mut bytes = BTreeMap::new();
+    for insn in run {
+        let target = match insn.mode {
+            Mode::Absolute => Target::Absolute(insn.operand as u16),
+            _ => Target::Long(insn.operand),
+        };
+        match insn.op {
+            Op::Lda => {
+                a_lo = Some(insn.operand as u8);
+                if !insn.m8 {
+                    a_hi = Some((insn.operand >> 8) as u8);
+                }
+            }
+            Op::Sta | Op::Stz => {
+                let (lo, hi) = if insn.op == Op::Stz {
+                    (Some(0), Some(0))
+                } else {
+                    (a_lo, a_hi)
+                };
+                if !target.is_wram() {
+                    return None;
+                }
+                bytes.insert(target.normalize(), lo?);
+                if !insn.m8 {
+                    if !target.offset(1).is_wram() {
+                        return None;
+                    }
+                    bytes.insert(target.offset(1).normalize(), hi?);
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(bytes)
+}
+
+struct Emitter {
+    insns: Vec<Insn>,
+    m8: bool,
+    x8: bool,
+    a_lo: Option<u8>,
+    a_hi: Option<u8>,
+}
+
+impl Emitter {
+    fn push(&mut self, op: Op, mode: Mode, operand: u32) {
+        self.insns.push(Insn {
+            op,
+            mode,
+            operand,
+            m8: self.m8,
+            x8: self.x8,
+        });
+    }
+
+    fn set_m8(&mut self, m8: bool) {
+        if self.m8 != m8 {
+            let op = if m8 { Op::Sep } else { Op::Rep };
+            self.push(op, Mode::Immediate8, FLAG_M as u32);
+            self.m8 = m8;
+        }
+    }
+
+    fn store(&mut self, target: Target, value: u16) {
+        let lo = value as u8;
+        let hi = (value >> 8) as u8;
+        let short = target.low_wram(if self.m8 { 1 } else { 2 });
+        if value == 0 {
+            if let Some(address) = short {
+                self.push(Op::Stz, Mode::Absolute, address as u32);
+                return;
+            }
+        }
+        let loaded = self.a_lo == Some(lo) && (self.m8 || self.a_hi == Some(hi));
+        if !loaded {
+            self.push(Op::Lda, Mode::ImmediateM, value as u32);
+            self.a_lo = Some(lo);
+            if !self.m8 {
+                self.a_hi = Some(hi);
+            }
+        }
+        match (short, target) {
+            (None, Target::Long(address)) => self.push(Op::Sta, Mode::AbsoluteLong, address),
+            (Some(address), _) | (None, Target::Absolute(address)) => {
+                self.push(Op::Sta, Mode::Absolute, address as u32)
+            }
+        }
+    }
+}
+
+// Rewrite one run given the bytes it should leave behind
+fn rewrite_run(bytes: &BTreeMap<Target, u8>, first: &Insn, last_m8: bool) -> Vec<Insn> {
+    let mut words: Vec<(Target, u16)> = vec![];
+    let mut singles: Vec<(Target, u16)> = vec![];
+    let mut iter = bytes.iter().peekable();
+    while let Some((target, lo)) = iter.next() {
+        match iter.peek() {
+            Some((next, hi)) if **next == target.offset(1) => {
+                words.push((*target, ((**hi as u16) << 8) | *lo as u16));
+                iter.next();
+            }
+            _ => singles.push((*target, *lo as u16)),
+        }
+    }
+    // Group by value so consecutive stores can share the same load, zeros
+    // first since those are mostly `stz` and leave A alone.
+    words.sort_by_key(|(target, value)| (*value, *target));
+    singles.sort_by_key(|(target, value)| (*value, *target));
+
+    let mut e = Emitter {
+        insns: vec![],
+        m8: first.m8,
+        x8: first.x8,
+        a_lo: None,
+        a_hi: None,
+    };
+    // Start with whichever width we are already in to save a mode switch
+    let order = if first.m8 {
+        [(true, &singles), (false, &words)]
+    } else {
+        [(false, &words), (true, &singles)]
+    };
+    for (m8, writes) in order {
+        if writes.is_empty() {
+            continue;
+        }
+        e.set_m8(m8);
+        for (target, value) in writes {
+            e.store(*target, *value);
+        }
+    }
+    e.set_m8(last_m8);
+    e.insns
+}
+
+// Optimise a block that starts with the given register widths. Blocks with
+// branches are left as they are since shrinking them would move the targets.
+pub fn optimize(code: &[u8], m8: bool, x8: bool) -> Vec<u8> {
+    let insns = disassemble(code, m8, x8);
+    if insns
+        .iter()
+        .any(|insn| matches!(insn.mode, Mode::Relative8 | Mode::Relative16))
+    {
+        return code.to_vec();
+    }
+    let mut r = vec![];
+    let mut i = 0;
+    while i < insns.len() {
+        if !is_simple(&insns[i]) {
+            r.push(insns[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < insns.len() && is_simple(&insns[i]) {
+            i += 1;
+        }
+        let run = &insns[start..i];
+        // A and the flags are only dead if nothing after the run reads them
+        let a_dead = match insns.get(i) {
+            None => true,
+            Some(next) => matches!(next.op, Op::Lda | Op::Pla | Op::Plp),
+        };
+        let last = run.last().unwrap();
+        let last_m8 = if last.is_mode_switch() {
+            last.op == Op::Sep
+        } else {
+            last.m8
+        };
+        let rewritten = match run_effect(run) {
+            Some(bytes) if a_dead => Some(rewrite_run(&bytes, &run[0], last_m8)),
+            _ => None,
+        };
+        match rewritten {
+            Some(new) if assemble(&new).len() < assemble(run).len() => r.extend(new),
+            _ => r.extend_from_slice(run),
+        }
+    }
+    assemble(&r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Bus, MemoryBus};
+    use crate::payload::Payload;
+    use crate::*;
+
+    // Run the block with and without the pass and check WRAM ends up the same
+    fn run_both(block: &[u8]) -> MemoryBus {
+        let mut buses = vec![];
+        for optimize in [false, true] {
+            let mut bus = MemoryBus::new();
+            let mut payload = Payload::new();
+            payload.set_optimize(optimize);
+            payload.push(block);
+            payload.run_on(&mut bus);
+            buses.push(bus);
+        }
+        for address in 0x7E_0000..0x80_0000 {
+            assert_eq!(
+                buses[0].read(address),
+                buses[1].read(address),
+                "${:06X} differs",
+                address
+            );
+        }
+        buses.pop().unwrap()
+    }
+
+    #[test]
+    fn merged_stores_leave_the_same_memory() {
+        let mut block = vec![];
+        block.extend_from_slice(&lda_immediate_u16(0));
+        block.extend_from_slice(&sta_absolute(0x0A10));
+        block.extend_from_slice(&lda_immediate_u16(0));
+        block.extend_from_slice(&sta_long(0x7E_0A12));
+        block.extend_from_slice(&lda_immediate_u16(0x4242));
+        block.extend_from_slice(&sta_long(0x7E_3000));
+        block.extend_from_slice(&lda_immediate_u16(0x4242));
+        block.extend_from_slice(&sta_absolute(0x0A14));
+        // A word straddling the end of low WRAM has to stay long
+        block.extend_from_slice(&lda_immediate_u16(0xABCD));
+        block.extend_from_slice(&sta_long(0x7E_1FFF));
+        block.push(0xe2); // sep #$20
+        block.push(0x20);
+        block.extend_from_slice(&lda_immediate_u8(0x05));
+        block.extend_from_slice(&sta_absolute(0x0A20));
+        block.push(0xc2); // rep #$20
+        block.push(0x20);
+        assert!(optimize(&block, false, false).len() < block.len());
+        let mut bus = run_both(&block);
+        assert_eq!(bus.read_u16(0x7E_0A14), 0x4242);
+        assert_eq!(bus.read_u16(0x7E_1FFF), 0xABCD);
+        assert_eq!(bus.read(0x7E_0A20), 0x05);
+    }
+
+    #[test]
+    fn abs_and_long_stores_to_one_byte_keep_their_order() {
+        let mut block = vec![];
+        block.extend_from_slice(&lda_immediate_u16(0x2222));
+        block.extend_from_slice(&sta_absolute(0x0A00));
+        block.extend_from_slice(&lda_immediate_u16(0x1111));
+        block.extend_from_slice(&sta_long(0x7E_0A00));
+        block.extend_from_slice(&lda_immediate_u16(0x1111));
+        block.extend_from_slice(&sta_long(0x7E_0A02));
+        block.extend_from_slice(&lda_immediate_u16(0x2222));
+        block.extend_from_slice(&sta_long(0x7E_0A04));
+        block.extend_from_slice(&lda_immediate_u16(0x1111));
+        block.extend_from_slice(&sta_absolute(0x0A04));
+        assert!(optimize(&block, false, false).len() < block.len());
+        let mut bus = run_both(&block);
+        assert_eq!(bus.read_u16(0x7E_0A00), 0x1111);
+        assert_eq!(bus.read_u16(0x7E_0A04), 0x1111);
+    }
+
+    #[test]
+    fn blocks_with_branches_are_left_alone() {
+        let mut block = vec![];
+        block.extend_from_slice(&lda_addr(0x0A00));
+        block.extend_from_slice(&beq(6));
+        block.extend_from_slice(&lda_immediate_u16(0x5555));
+        block.extend_from_slice(&sta_absolute(0x0A02));
+        block.extend_from_slice(&lda_immediate_u16(0x5555));
+        block.extend_from_slice(&sta_absolute(0x0A04));
+        block.extend_from_slice(&lda_immediate_u16(0x5555));
+        block.extend_from_slice(&sta_absolute(0x0A06));
+        assert_eq!(optimize(&block, false, false), block);
+        let mut bus = run_both(&block);
+        // $0A00 is zero so the first store is skipped
+        assert_eq!(bus.read_u16(0x7E_0A02), 0);
+        assert_eq!(bus.read_u16(0x7E_0A04), 0x5555);
+        assert_eq!(bus.read_u16(0x7E_0A06), 0x5555);
+    }
+}
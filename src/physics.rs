@@ -0,0 +1,444 @@
+//! Samus' pose and movement.
+//!
+//! Kept apart from `Samus` on purpose: these change every frame, so they'd
+//! be stale by the time a dump gets applied. Writes only touch the fields
+//! that were asked for, the game works the rest out on its next frame.
+
+use crate::payload::{Payload, PayloadArgs};
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+use std::fmt;
+
+pub const POSE: u16 = 0x0A1C;
+// High byte of the pose direction/movement type word at $0A1E
+pub const MOVEMENT_TYPE: u16 = 0x0A1F;
+pub const SHINESPARK_TIMER: u16 = 0x0A68;
+pub const Y_SUBSPEED: u16 = 0x0B2C;
+pub const Y_SPEED: u16 = 0x0B2E;
+pub const SPEED_BOOST_COUNTER: u16 = 0x0B3E;
+// 0 to 4, 4 being blue
+pub const SPEED_BOOST_LEVEL: u16 = 0x0B3F;
+pub const X_SPEED: u16 = 0x0B42;
+pub const X_SUBSPEED: u16 = 0x0B44;
+pub const X_MOMENTUM: u16 = 0x0B46;
+pub const X_SUBMOMENTUM: u16 = 0x0B48;
+pub const INVINCIBILITY_TIMER: u16 = 0x18A8;
+
+// The poses worth naming, there are a couple hundred of them
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Pose {
+    FacingForward,
+    StandingRight,
+    StandingLeft,
+    RunningRight,
+    RunningLeft,
+    SpinJumpRight,
+    SpinJumpLeft,
+    SpaceJumpRight,
+    SpaceJumpLeft,
+    MorphBallRight,
+    CrouchingRight,
+    CrouchingLeft,
+    FallingRight,
+    FallingLeft,
+    MorphBallLeft,
+    JumpRight,
+    JumpLeft,
+    ScrewAttackRight,
+    ScrewAttackLeft,
+    WallJumpRight,
+    WallJumpLeft,
+    ShinesparkWindupRight,
+    ShinesparkWindupLeft,
+    ShinesparkRight,
+    ShinesparkLeft,
+    ShinesparkUpRight,
+    ShinesparkUpLeft,
+    ShinesparkDiagonalRight,
+    ShinesparkDiagonalLeft,
+    Other(u16),
+}
+
+static POSES: &[(u16, Pose)] = &[
+    (0x00, Pose::FacingForward),
+    (0x01, Pose::StandingRight),
+    (0x02, Pose::StandingLeft),
+    (0x09, Pose::RunningRight),
+    (0x0A, Pose::RunningLeft),
+    (0x19, Pose::SpinJumpRight),
+    (0x1A, Pose::SpinJumpLeft),
+    (0x1B, Pose::SpaceJumpRight),
+    (0x1C, Pose::SpaceJumpLeft),
+    (0x1D, Pose::MorphBallRight),
+    (0x27, Pose::CrouchingRight),
+    (0x28, Pose::CrouchingLeft),
+    (0x29, Pose::FallingRight),
+    (0x2A, Pose::FallingLeft),
+    (0x41, Pose::MorphBallLeft),
+    (0x4D, Pose::JumpRight),
+    (0x4E, Pose::JumpLeft),
+    (0x81, Pose::ScrewAttackRight),
+    (0x82, Pose::ScrewAttackLeft),
+    (0x83, Pose::WallJumpRight),
+    (0x84, Pose::WallJumpLeft),
+    (0xC7, Pose::ShinesparkWindupRight),
+    (0xC8, Pose::ShinesparkWindupLeft),
+    (0xC9, Pose::ShinesparkRight),
+    (0xCA, Pose::ShinesparkLeft),
+    (0xCB, Pose::ShinesparkUpRight),
+    (0xCC, Pose::ShinesparkUpLeft),
+    (0xCD, Pose::ShinesparkDiagonalRight),
+    (0xCE, Pose::ShinesparkDiagonalLeft),
+];
+
+impl From<u16> for Pose {
+    fn from(pose: u16) -> Pose {
+        POSES
+            .iter()
+            .find(|(p, _)| *p == pose)
+            .map(|(_, pose)| *pose)
+            .unwrap_or(Pose::Other(pose))
+    }
+}
+
+impl From<Pose> for u16 {
+    fn from(pose: Pose) -> u16 {
+        match pose {
+            Pose::Other(pose) => pose,
+            pose => POSES.iter().find(|(_, p)| *p == pose).unwrap().0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MovementType {
+    Standing,
+    Running,
+    NormalJumping,
+    SpinJumping,
+    MorphBallOnGround,
+    Crouching,
+    Falling,
+    MorphBallFalling,
+    Knockback,
+    TurningAround,
+    Transitioning,
+    Moonwalking,
+    SpringBallOnGround,
+    SpringBallInAir,
+    SpringBallFalling,
+    WallJumping,
+    RanIntoWall,
+    Grappling,
+    TurningAroundJumping,
+    TurningAroundFalling,
+    DamageBoost,
+    GrabbedByDraygon,
+    Shinespark,
+    Other(u8),
+}
+
+static MOVEMENT_TYPES: &[(u8, MovementType)] = &[
+    (0x00, MovementType::Standing),
+    (0x01, MovementType::Running),
+    (0x02, MovementType::NormalJumping),
+    (0x03, MovementType::SpinJumping),
+    (0x04, MovementType::MorphBallOnGround),
+    (0x05, MovementType::Crouching),
+    (0x06, MovementType::Falling),
+    (0x08, MovementType::MorphBallFalling),
+    (0x0A, MovementType::Knockback),
+    (0x0E, MovementType::TurningAround),
+    (0x0F, MovementType::Transitioning),
+    (0x10, MovementType::Moonwalking),
+    (0x11, MovementType::SpringBallOnGround),
+    (0x12, MovementType::SpringBallInAir),
+    (0x13, MovementType::SpringBallFalling),
+    (0x14, MovementType::WallJumping),
+    (0x15, MovementType::RanIntoWall),
+    (0x16, MovementType::Grappling),
+    (0x17, MovementType::TurningAroundJumping),
+    (0x18, MovementType::TurningAroundFalling),
+    (0x19, MovementType::DamageBoost),
+    (0x1A, MovementType::GrabbedByDraygon),
+    // Also crystal flashing and being drained by a metroid
+    (0x1B, MovementType::Shinespark),
+];
+
+impl From<u8> for MovementType {
+    fn from(movement: u8) -> MovementType {
+        MOVEMENT_TYPES
+            .iter()
+            .find(|(m, _)| *m == movement)
+            .map(|(_, movement)| *movement)
+            .unwrap_or(MovementType::Other(movement))
+    }
+}
+
+impl From<MovementType> for u8 {
+    fn from(movement: MovementType) -> u8 {
+        match movement {
+            MovementType::Other(movement) => movement,
+            movement => {
+                MOVEMENT_TYPES
+                    .iter()
+                    .find(|(_, m)| *m == movement)
+                    .unwrap()
+                    .0
+            }
+        }
+    }
+}
+
+// Parse a name from `table` ignoring case, or a hex value
+fn parse_named<T: fmt::Debug + Copy, V: TryFrom<u16>>(
+    table: &[(V, T)],
+    s: &str,
+) -> Option<Result<T, V>> {
+    if let Some((_, value)) = table
+        .iter()
+        .find(|(_, v)| format!("{:?}", v).eq_ignore_ascii_case(s))
+    {
+        return Some(Ok(*value));
+    }
+    let value = u16::from_str_radix(s.trim_start_matches("0x").trim_start_matches('$'), 16).ok()?;
+    V::try_from(value).ok().map(Err)
+}
+
+pub fn parse_pose(s: &str) -> Result<Pose, String> {
+    match parse_named(POSES, s) {
+        Some(Ok(pose)) => Ok(pose),
+        Some(Err(pose)) => Ok(pose.into()),
+        None => Err(format!("unknown pose {:?}", s)),
+    }
+}
+
+pub fn parse_movement_type(s: &str) -> Result<MovementType, String> {
+    match parse_named(MOVEMENT_TYPES, s) {
+        Some(Ok(movement)) => Ok(movement),
+        Some(Err(movement)) => Ok(movement.into()),
+        None => Err(format!("unknown movement type {:?}", s)),
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Physics {
+    pub pose: Pose,
+    pub movement_type: MovementType,
+    pub x_speed: u16,
+    pub x_subspeed: u16,
+    pub x_momentum: u16,
+    pub x_submomentum: u16,
+    pub y_speed: u16,
+    pub y_subspeed: u16,
+    pub speed_boost_counter: u8,
+    pub speed_boost_level: u8,
+    pub shinespark_timer: u16,
+    pub invincibility_timer: u16,
+}
+
+pub fn get_physics(client: &mut SyncClient) -> Result<Physics, Box<dyn Error>> {
+    // One request so the fields all come from the same moment. The FXPak
+    // only takes a few ranges at a time, so read the runs the fields sit in.
+    let data = client.get_addresses(&[
+        (WRAM + POSE as u32, 4),
+        (
+            WRAM + Y_SUBSPEED as u32,
+            (X_SUBMOMENTUM - Y_SUBSPEED + 2) as usize,
+        ),
+        (WRAM + SHINESPARK_TIMER as u32, 2),
+        (WRAM + INVINCIBILITY_TIMER as u32, 2),
+    ])?;
+    let word = |d: &[u8], at: u16| u16::from_le_bytes([d[at as usize], d[at as usize + 1]]);
+    let speed = |address: u16| word(&data[1], address - Y_SUBSPEED);
+    let speed_boost = speed(SPEED_BOOST_COUNTER);
+    Ok(Physics {
+        pose: word(&data[0], 0).into(),
+        movement_type: data[0][(MOVEMENT_TYPE - POSE) as usize].into(),
+        x_speed: speed(X_SPEED),
+        x_subspeed: speed(X_SUBSPEED),
+        x_momentum: speed(X_MOMENTUM),
+        x_submomentum: speed(X_SUBMOMENTUM),
+        y_speed: speed(Y_SPEED),
+        y_subspeed: speed(Y_SUBSPEED),
+        speed_boost_counter: speed_boost as u8,
+        speed_boost_level: (speed_boost >> 8) as u8,
+        shinespark_timer: word(&data[2], 0),
+        invincibility_timer: word(&data[3], 0),
+    })
+}
+
+impl Physics {
+    // Write the fields that differ from `before`
+    pub fn diff_asm(&self, before: &Physics) -> Vec<u8> {
+        let mut r = Vec::new();
+        let words = [
+            (POSE, u16::from(before.pose), u16::from(self.pose)),
+            (X_SPEED, before.x_speed, self.x_speed),
+            (X_SUBSPEED, before.x_subspeed, self.x_subspeed),
+            (X_MOMENTUM, before.x_momentum, self.x_momentum),
+            (X_SUBMOMENTUM, before.x_submomentum, self.x_submomentum),
+            (Y_SPEED, before.y_speed, self.y_speed),
+            (Y_SUBSPEED, before.y_subspeed, self.y_subspeed),
+            (
+                SHINESPARK_TIMER,
+                before.shinespark_timer,
+                self.shinespark_timer,
+            ),
+            (
+                INVINCIBILITY_TIMER,
+                before.invincibility_timer,
+                self.invincibility_timer,
+            ),
+        ];
+        for (address, before, after) in words {
+            if before != after {
+                r.extend_from_slice(&lda_immediate_u16(after));
+                r.extend_from_slice(&sta_absolute(address));
+            }
+        }
+        let bytes = [
+            (
+                MOVEMENT_TYPE,
+                u8::from(before.movement_type),
+                u8::from(self.movement_type),
+            ),
+            (
+                SPEED_BOOST_COUNTER,
+                before.speed_boost_counter,
+                self.speed_boost_counter,
+            ),
+            (
+                SPEED_BOOST_LEVEL,
+                before.speed_boost_level,
+                self.speed_boost_level,
+            ),
+        ];
+        if bytes.iter().all(|(_, before, after)| before == after) {
+            return r;
+        }
+        // sep #$20
+        r.push(0xe2);
+        r.push(0x20);
+        for (address, before, after) in bytes {
+            if before != after {
+                r.extend_from_slice(&lda_immediate_u8(after));
+                r.extend_from_slice(&sta_absolute(address));
+            }
+        }
+        // rep #$20
+        r.push(0xc2);
+        r.push(0x20);
+        r
+    }
+}
+
+impl fmt::Display for Physics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pose:           {:?}", self.pose)?;
+        writeln!(f, "movement type:  {:?}", self.movement_type)?;
+        writeln!(
+            f,
+            "x speed:        {}.{:04X}, momentum {}.{:04X}",
+            self.x_speed, self.x_subspeed, self.x_momentum, self.x_submomentum
+        )?;
+        writeln!(
+            f,
+            "y speed:        {}.{:04X}",
+            self.y_speed, self.y_subspeed
+        )?;
+        writeln!(
+            f,
+            "speed boost:    level {}, counter {}",
+            self.speed_boost_level, self.speed_boost_counter
+        )?;
+        writeln!(f, "shinespark:     {}", self.shinespark_timer)?;
+        write!(f, "i-frames:       {}", self.invincibility_timer)
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct PhysicsArgs {
+    #[clap(subcommand)]
+    action: PhysicsAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum PhysicsAction {
+    /// Print Samus' pose and movement
+    Show,
+    /// Set any of Samus' pose and movement fields
+    Set {
+        /// Pose by name or hex value
+        #[clap(long, parse(try_from_str = parse_pose))]
+        pose: Option<Pose>,
+        /// Movement type by name or hex value
+        #[clap(long, parse(try_from_str = parse_movement_type))]
+        movement_type: Option<MovementType>,
+        #[clap(long)]
+        x_speed: Option<u16>,
+        #[clap(long)]
+        x_momentum: Option<u16>,
+        #[clap(long)]
+        y_speed: Option<u16>,
+        #[clap(long)]
+        speed_boost_counter: Option<u8>,
+        /// 0 to 4, 4 is blue
+        #[clap(long)]
+        speed_boost_level: Option<u8>,
+        /// Frames left to shinespark
+        #[clap(long)]
+        shinespark_timer: Option<u16>,
+        /// Frames of invincibility
+        #[clap(long)]
+        iframes: Option<u16>,
+    },
+}
+
+pub fn run(
+    client: &mut SyncClient,
+    args: PhysicsArgs,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    let before = get_physics(client)?;
+    let mut physics = before;
+    match args.action {
+        PhysicsAction::Show => {
+            println!("{}", physics);
+            return Ok(());
+        }
+        PhysicsAction::Set {
+            pose,
+            movement_type,
+            x_speed,
+            x_momentum,
+            y_speed,
+            speed_boost_counter,
+            speed_boost_level,
+            shinespark_timer,
+            iframes,
+        } => {
+            if speed_boost_level.unwrap_or(0) > 4 {
+                return Err("the speed boost level goes up to 4".into());
+            }
+            physics.pose = pose.unwrap_or(physics.pose);
+            physics.movement_type = movement_type.unwrap_or(physics.movement_type);
+            physics.x_speed = x_speed.unwrap_or(physics.x_speed);
+            physics.x_momentum = x_momentum.unwrap_or(physics.x_momentum);
+            physics.y_speed = y_speed.unwrap_or(physics.y_speed);
+            physics.speed_boost_counter =
+                speed_boost_counter.unwrap_or(physics.speed_boost_counter);
+            physics.speed_boost_level = speed_boost_level.unwrap_or(physics.speed_boost_level);
+            physics.shinespark_timer = shinespark_timer.unwrap_or(physics.shinespark_timer);
+            physics.invincibility_timer = iframes.unwrap_or(physics.invincibility_timer);
+        }
+    }
+    let asm = physics.diff_asm(&before);
+    if asm.is_empty() {
+        return Ok(());
+    }
+    let mut payload = Payload::from_args(payload_args);
+    payload.push(&asm);
+    payload.run(client)
+}
@@ -0,0 +1,102 @@
+//! Samus' maximums in pickup units.
+//!
+//! The game only stores the raw maximums, these convert them to and from
+//! the number of tanks and packs it takes to get there.
+
+use crate::*;
+use std::error::Error;
+
+// What a single pickup adds
+pub const ETANK: u16 = 100;
+pub const RESERVE_TANK: u16 = 100;
+pub const MISSILE_PACK: u16 = 5;
+pub const SUPER_PACK: u16 = 5;
+pub const PB_PACK: u16 = 5;
+// Max HP before picking anything up
+pub const BASE_HP: u16 = 99;
+
+// `base` plus `count` pickups of `each`, or an error if that won't fit in
+// the game's word
+fn maximum(base: u16, count: u16, each: u16, what: &str) -> Result<u16, Box<dyn Error>> {
+    count
+        .checked_mul(each)
+        .and_then(|n| n.checked_add(base))
+        .ok_or_else(|| format!("{} {} is too many, the maximum wouldn't fit", count, what).into())
+}
+
+impl Samus {
+    pub fn etanks(&self) -> u16 {
+        self.max_hp.saturating_sub(BASE_HP) / ETANK
+    }
+
+    pub fn set_etanks(&mut self, etanks: u16) -> Result<(), Box<dyn Error>> {
+        self.max_hp = maximum(BASE_HP, etanks, ETANK, "etanks")?;
+        Ok(())
+    }
+
+    pub fn reserve_tanks(&self) -> u16 {
+        self.max_reserve_hp / RESERVE_TANK
+    }
+
+    // Picking up the first reserve tank switches reserves to auto, taking
+    // them all away turns them off again
+    pub fn set_reserve_tanks(&mut self, tanks: u16) -> Result<(), Box<dyn Error>> {
+        self.max_reserve_hp = maximum(0, tanks, RESERVE_TANK, "reserve tanks")?;
+        if tanks == 0 {
+            self.reserve_mode = ReserveMode::None;
+        } else if self.reserve_mode == ReserveMode::None {
+            self.reserve_mode = ReserveMode::Auto;
+        }
+        Ok(())
+    }
+
+    pub fn missile_packs(&self) -> u16 {
+        self.max_missiles / MISSILE_PACK
+    }
+
+    pub fn set_missile_packs(&mut self, packs: u16) -> Result<(), Box<dyn Error>> {
+        self.max_missiles = maximum(0, packs, MISSILE_PACK, "missile packs")?;
+        Ok(())
+    }
+
+    pub fn super_packs(&self) -> u16 {
+        self.max_supers / SUPER_PACK
+    }
+
+    pub fn set_super_packs(&mut self, packs: u16) -> Result<(), Box<dyn Error>> {
+        self.max_supers = maximum(0, packs, SUPER_PACK, "super packs")?;
+        Ok(())
+    }
+
+    pub fn pb_packs(&self) -> u16 {
+        self.max_pbs / PB_PACK
+    }
+
+    pub fn set_pb_packs(&mut self, packs: u16) -> Result<(), Box<dyn Error>> {
+        self.max_pbs = maximum(0, packs, PB_PACK, "power bomb packs")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn too_many_pickups_is_an_error() {
+        let mut samus = Samus::default();
+        samus.set_etanks(14).unwrap();
+        assert_eq!(samus.max_hp, 1499);
+        assert!(samus.set_etanks(1000).is_err());
+        // Left alone on error
+        assert_eq!(samus.max_hp, 1499);
+        samus.set_etanks(654).unwrap();
+        assert_eq!(samus.max_hp, 65499);
+        assert!(samus.set_etanks(655).is_err());
+        samus.set_missile_packs(13107).unwrap();
+        assert_eq!(samus.max_missiles, 65535);
+        assert!(samus.set_missile_packs(13108).is_err());
+        assert!(samus.set_reserve_tanks(u16::MAX).is_err());
+        assert_eq!(samus.reserve_mode, ReserveMode::None);
+    }
+}
@@ -0,0 +1,269 @@
+//! Post-load modifications, the objects that live in the room's level data:
+//! items, doors, gates, crumble blocks, save stations and so on.
+//!
+//! There are 40 PLM slots. The IDs are at $1C37, the pointer to the PLM's
+//! header in bank $84, and the block each one sits on is at $1C87 as a byte
+//! offset into the level data. Deleting a PLM clears its ID so the game stops
+//! running it, and turns its block into plain air so whatever it wrote into
+//! the level data goes too. The tile on screen only redraws once it's
+//! scrolled back into view.
+
+use crate::payload::{Payload, PayloadArgs};
+use crate::position::{get_room_size, RoomSize};
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+
+pub const PLM_IDS: u16 = 0x1C37;
+pub const PLM_BLOCKS: u16 = 0x1C87;
+pub const PLM_SLOTS: u16 = 40;
+// Two bytes per block, and one BTS byte per block
+pub const LEVEL_DATA: u32 = 0x7F_0002;
+pub const BTS: u32 = 0x7F_6402;
+// Air, with the blank tile
+pub const AIR_BLOCK: u16 = 0x00FF;
+
+// Item PLMs come in three runs of the same items: shown, in a chozo orb and
+// hidden in a shot block
+const ITEM_PLMS: u16 = 0xEED7;
+const CHOZO_ITEM_PLMS: u16 = 0xEF2B;
+const HIDDEN_ITEM_PLMS: u16 = 0xEF7F;
+static ITEM_PLM_NAMES: [&str; 21] = [
+    "Energy Tank",
+    "Missile",
+    "Super Missile",
+    "Power Bomb",
+    "Bombs",
+    "Charge Beam",
+    "Ice Beam",
+    "Hi-Jump Boots",
+    "Speed Booster",
+    "Wave Beam",
+    "Spazer",
+    "Spring Ball",
+    "Varia Suit",
+    "Gravity Suit",
+    "X-Ray Scope",
+    "Plasma Beam",
+    "Grapple Beam",
+    "Space Jump",
+    "Screw Attack",
+    "Morph Ball",
+    "Reserve Tank",
+];
+
+fn item_plm_name(id: u16) -> Option<(&'static str, &'static str)> {
+    for (base, kind) in [
+        (ITEM_PLMS, ""),
+        (CHOZO_ITEM_PLMS, " (chozo)"),
+        (HIDDEN_ITEM_PLMS, " (hidden)"),
+    ] {
+        if id >= base && (id - base).is_multiple_of(4) {
+            if let Some(name) = ITEM_PLM_NAMES.get(((id - base) / 4) as usize) {
+                return Some((name, kind));
+            }
+        }
+    }
+    None
+}
+
+pub fn plm_name(id: u16) -> Option<String> {
+    item_plm_name(id).map(|(name, kind)| format!("{}{}", name, kind))
+}
+
+pub fn is_item(id: u16) -> bool {
+    item_plm_name(id).is_some()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Plm {
+    pub slot: u16,
+    pub id: u16,
+    // Byte offset into the level data, two bytes per block
+    pub block_index: u16,
+}
+
+impl Plm {
+    pub fn name(&self) -> Option<String> {
+        plm_name(self.id)
+    }
+
+    // The block the PLM is on, in blocks from the room's top left corner
+    pub fn block(&self, size: RoomSize) -> (u16, u16) {
+        let block = self.block_index / 2;
+        if size.width == 0 {
+            return (block, 0);
+        }
+        (block % size.width, block / size.width)
+    }
+}
+
+pub fn get_plms(client: &mut SyncClient) -> Result<Vec<Plm>, Box<dyn Error>> {
+    // The ID and block tables are back to back
+    let data = client.get_address(WRAM + PLM_IDS as u32, (PLM_SLOTS * 4) as usize)?;
+    let word = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+    Ok((0..PLM_SLOTS)
+        .map(|slot| Plm {
+            slot,
+            id: word(slot as usize * 2),
+            block_index: word((PLM_SLOTS + slot) as usize * 2),
+        })
+        .filter(|p| p.id != 0)
+        .collect())
+}
+
+pub fn delete_asm(slot: u16) -> Vec<u8> {
+    let id = PLM_IDS + slot * 2;
+    let mut clear = Vec::new();
+    clear.extend_from_slice(&stz_absolute(id));
+    // The block index is read when this runs, not when it's built
+    clear.extend_from_slice(&ldx_addr(PLM_BLOCKS + slot * 2));
+    clear.extend_from_slice(&lda_immediate_u16(AIR_BLOCK));
+    clear.extend_from_slice(&sta_long_x(LEVEL_DATA));
+    clear.extend_from_slice(&txa());
+    clear.extend_from_slice(&lsr());
+    clear.extend_from_slice(&tax());
+    // sep #$20
+    clear.push(0xe2);
+    clear.push(0x20);
+    clear.extend_from_slice(&lda_immediate_u8(0));
+    clear.extend_from_slice(&sta_long_x(BTS));
+    // rep #$20
+    clear.push(0xc2);
+    clear.push(0x20);
+    // An empty slot's block index is stale, leave its block alone
+    let mut r = Vec::new();
+    r.extend_from_slice(&lda_addr(id));
+    r.extend_from_slice(&beq(clear.len() as i8));
+    r.extend_from_slice(&clear);
+    r
+}
+
+// Delete every PLM in the room, a block per slot since all of them
+// together don't fit in the CMD space
+pub fn delete_plms() -> Vec<Vec<u8>> {
+    (0..PLM_SLOTS).map(delete_asm).collect()
+}
+
+#[derive(clap::Args, Debug)]
+pub struct PlmsArgs {
+    #[clap(subcommand)]
+    action: PlmsAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum PlmsAction {
+    /// List the PLMs in the current room
+    List,
+    /// Delete PLMs by slot
+    Delete {
+        slots: Vec<u16>,
+        /// Delete every PLM in the room
+        #[clap(long)]
+        all: bool,
+        /// Delete every item PLM in the room
+        #[clap(long)]
+        items: bool,
+    },
+}
+
+pub fn run(
+    client: &mut SyncClient,
+    args: PlmsArgs,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    let plms = get_plms(client)?;
+    let mut payload = Payload::from_args(payload_args);
+    match args.action {
+        PlmsAction::List => {
+            let size = get_room_size(client)?;
+            for plm in &plms {
+                let (x, y) = plm.block(size);
+                println!(
+                    "{:2} ${:04X} block ({:3}, {:3}) {}",
+                    plm.slot,
+                    plm.id,
+                    x,
+                    y,
+                    plm.name().unwrap_or_default()
+                );
+            }
+            return Ok(());
+        }
+        PlmsAction::Delete { slots, all, items } => {
+            for plm in &plms {
+                if all || (items && is_item(plm.id)) || slots.contains(&plm.slot) {
+                    payload.push(&delete_asm(plm.slot));
+                }
+            }
+            if let Some(slot) = slots.iter().find(|s| !plms.iter().any(|p| p.slot == **s)) {
+                return Err(format!("no PLM in slot {}", slot).into());
+            }
+        }
+    }
+    if payload.is_empty() {
+        return Ok(());
+    }
+    payload.run(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Bus, MemoryBus};
+
+    #[test]
+    fn delete_clears_the_block() {
+        let mut bus = MemoryBus::new();
+        // Slot 3 holds a PLM on block 5, slot 4 is empty with a stale index
+        bus.load(0x7E_0000 + (PLM_IDS + 6) as u32, &[0xD7, 0xEE, 0x00, 0x00]);
+        bus.load(
+            0x7E_0000 + (PLM_BLOCKS + 6) as u32,
+            &[0x0A, 0x00, 0x00, 0x00],
+        );
+        bus.load(LEVEL_DATA, &[0x23, 0x81]);
+        bus.load(LEVEL_DATA + 0x0A, &[0x23, 0x81]);
+        bus.load(BTS, &[0x45]);
+        bus.load(BTS + 5, &[0x45]);
+        let mut payload = Payload::new();
+        payload.push(&delete_asm(3));
+        payload.push(&delete_asm(4));
+        payload.run_on(&mut bus);
+        assert_eq!(bus.read_u16(0x7E_0000 + (PLM_IDS + 6) as u32), 0);
+        assert_eq!(bus.read_u16(LEVEL_DATA + 0x0A), AIR_BLOCK);
+        assert_eq!(bus.read(BTS + 5), 0);
+        // Block 0 belongs to nobody and stays
+        assert_eq!(bus.read_u16(LEVEL_DATA), 0x8123);
+        assert_eq!(bus.read(BTS), 0x45);
+    }
+
+    #[test]
+    fn delete_every_plm() {
+        let mut bus = MemoryBus::new();
+        for slot in 0..PLM_SLOTS as u32 {
+            bus.load(0x7E_0000 + PLM_IDS as u32 + slot * 2, &[0xD7, 0xEE]);
+            // Every other block, leaving block 0 alone
+            let block = 4 * (slot + 1) as u16;
+            bus.load(
+                0x7E_0000 + PLM_BLOCKS as u32 + slot * 2,
+                &block.to_le_bytes(),
+            );
+            bus.load(LEVEL_DATA + block as u32, &[0x23, 0x81]);
+            bus.load(BTS + block as u32 / 2, &[0x45]);
+        }
+        let mut payload = Payload::new();
+        for block in delete_plms() {
+            payload.push(&block);
+        }
+        let programs = payload.build().unwrap();
+        assert!(programs.len() > 1);
+        assert!(programs.iter().all(|p| p.len() <= payload::CMD_SIZE));
+        payload.run_on(&mut bus);
+        for slot in 0..PLM_SLOTS as u32 {
+            let block = 4 * (slot + 1);
+            assert_eq!(bus.read_u16(0x7E_0000 + PLM_IDS as u32 + slot * 2), 0);
+            assert_eq!(bus.read_u16(LEVEL_DATA + block), AIR_BLOCK);
+            assert_eq!(bus.read(BTS + block / 2), 0);
+        }
+    }
+}
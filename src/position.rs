@@ -0,0 +1,187 @@
+//! Moving Samus around the current room.
+//!
+//! Positions are Samus' center in pixels from the room's top left corner,
+//! rooms are measured in 16x16 blocks. Everything is checked against the
+//! size of the room she's in so she can't be put out of bounds.
+
+use crate::diff::SamusDiff;
+use crate::payload::{Payload, PayloadArgs};
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+
+pub const ROOM_WIDTH: u16 = 0x07A5;
+pub const ROOM_HEIGHT: u16 = 0x07A7;
+pub const BLOCK_SIZE: i32 = 16;
+
+// Room size in blocks
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RoomSize {
+    pub width: u16,
+    pub height: u16,
+}
+
+pub fn get_room_size(client: &mut SyncClient) -> Result<RoomSize, Box<dyn Error>> {
+    Ok(RoomSize {
+        width: get_u16(client, WRAM + ROOM_WIDTH as u32)?,
+        height: get_u16(client, WRAM + ROOM_HEIGHT as u32)?,
+    })
+}
+
+fn out_of_range(x: i32, y: i32) -> Box<dyn Error> {
+    format!("({}, {}) is way outside the room", x, y).into()
+}
+
+// A move in blocks or pixels, in pixels
+pub fn scale_move(dx: i32, dy: i32, blocks: bool) -> Result<(i32, i32), Box<dyn Error>> {
+    let scale = if blocks { BLOCK_SIZE } else { 1 };
+    dx.checked_mul(scale)
+        .zip(dy.checked_mul(scale))
+        .ok_or_else(|| out_of_range(dx, dy))
+}
+
+impl Samus {
+    // Put Samus at (x, y) pixels, dropping the subpixels
+    pub fn set_position(&mut self, size: RoomSize, x: i32, y: i32) -> Result<(), Box<dyn Error>> {
+        let (width, height) = (
+            size.width as i32 * BLOCK_SIZE,
+            size.height as i32 * BLOCK_SIZE,
+        );
+        if x < 0 || y < 0 || x >= width || y >= height {
+            return Err(format!(
+                "({}, {}) is outside the room, which is {}x{} pixels",
+                x, y, width, height
+            )
+            .into());
+        }
+        self.x_position = x as u16;
+        self.y_position = y as u16;
+        self.x_subposition = 0;
+        self.y_subposition = 0;
+        Ok(())
+    }
+
+    pub fn move_by(&mut self, size: RoomSize, dx: i32, dy: i32) -> Result<(), Box<dyn Error>> {
+        let x = (self.x_position as i32).checked_add(dx);
+        let y = (self.y_position as i32).checked_add(dy);
+        let (x, y) = x.zip(y).ok_or_else(|| out_of_range(dx, dy))?;
+        self.set_position(size, x, y)
+    }
+
+    // Center Samus on block (x, y)
+    pub fn set_block(&mut self, size: RoomSize, x: i32, y: i32) -> Result<(), Box<dyn Error>> {
+        let center = |block: i32| {
+            block
+                .checked_mul(BLOCK_SIZE)
+                .and_then(|p| p.checked_add(BLOCK_SIZE / 2))
+        };
+        let (px, py) = center(x).zip(center(y)).ok_or_else(|| out_of_range(x, y))?;
+        self.set_position(size, px, py)
+    }
+
+    // The block Samus' center is in
+    pub fn block(&self) -> (i32, i32) {
+        (
+            self.x_position as i32 / BLOCK_SIZE,
+            self.y_position as i32 / BLOCK_SIZE,
+        )
+    }
+
+    // Center Samus on the block she's in
+    pub fn snap_to_block(&mut self, size: RoomSize) -> Result<(), Box<dyn Error>> {
+        let (x, y) = self.block();
+        self.set_block(size, x, y)
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct PositionArgs {
+    #[clap(subcommand)]
+    action: PositionAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum PositionAction {
+    /// Print Samus' position and the room size
+    Show,
+    /// Move Samus relative to where she is, right and down are positive
+    #[clap(allow_negative_numbers = true)]
+    Move {
+        dx: i32,
+        dy: i32,
+        /// Move by blocks instead of pixels
+        #[clap(long)]
+        blocks: bool,
+    },
+    /// Center Samus on a block
+    Set { x: i32, y: i32 },
+    /// Center Samus on the block she's in
+    Snap,
+}
+
+pub fn run(
+    client: &mut SyncClient,
+    args: PositionArgs,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    let before = get_samus(client)?;
+    let size = get_room_size(client)?;
+    let mut samus = before.clone();
+    match args.action {
+        PositionAction::Show => {
+            let (x, y) = samus.block();
+            println!(
+                "({}, {}) pixels, block ({}, {}), room is {}x{} blocks",
+                samus.x_position, samus.y_position, x, y, size.width, size.height
+            );
+            return Ok(());
+        }
+        PositionAction::Move { dx, dy, blocks } => {
+            let (dx, dy) = scale_move(dx, dy, blocks)?;
+            samus.move_by(size, dx, dy)?;
+        }
+        PositionAction::Set { x, y } => samus.set_block(size, x, y)?,
+        PositionAction::Snap => samus.snap_to_block(size)?,
+    }
+    let diff = SamusDiff::new(&before, &samus);
+    if diff.is_empty() {
+        return Ok(());
+    }
+    let mut payload = Payload::from_args(payload_args);
+    payload.push(&diff.to_asm(true));
+    payload.run(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: RoomSize = RoomSize {
+        width: 2,
+        height: 1,
+    };
+
+    #[test]
+    fn moves_stay_in_the_room() {
+        let mut samus = Samus::default();
+        samus.set_block(SIZE, 1, 0).unwrap();
+        assert_eq!((samus.x_position, samus.y_position), (24, 8));
+        let (dx, dy) = scale_move(-1, 0, true).unwrap();
+        samus.move_by(SIZE, dx, dy).unwrap();
+        assert_eq!((samus.x_position, samus.y_position), (8, 8));
+        assert!(samus.move_by(SIZE, -9, 0).is_err());
+        assert!(samus.set_block(SIZE, 2, 0).is_err());
+        assert_eq!((samus.x_position, samus.y_position), (8, 8));
+    }
+
+    #[test]
+    fn huge_moves_are_errors() {
+        let mut samus = Samus::default();
+        assert!(scale_move(i32::MAX, 0, true).is_err());
+        assert!(scale_move(0, i32::MIN, true).is_err());
+        assert_eq!(scale_move(i32::MAX, 0, false).unwrap(), (i32::MAX, 0));
+        assert!(samus.move_by(SIZE, i32::MAX, 0).is_err());
+        assert!(samus.set_block(SIZE, i32::MAX, 0).is_err());
+        assert!(samus.set_block(SIZE, 0, i32::MIN).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+//! Patching the ROM while the game runs.
+//!
+//! The FXPak keeps the ROM in SDRAM and usb2snes maps it at the bottom of
+//! the SNES space, by file offset. Super Metroid is LoROM so a SNES address
+//! like $A0:8000 has to be converted first. A `PatchSession` remembers what
+//! it overwrote and puts it back when it's dropped, unless it's kept. Drop
+//! doesn't run when the process is killed, so `rom patch` catches ctrl-c
+//! itself and reverts the same as for enter.
+//!
+//! The console keeps running while the bytes go in, so patching code that
+//! could be running at that moment is at your own risk.
+
+use crate::usb2snes::SyncClient;
+use std::error::Error;
+
+// usb2snes won't take much more in one PutAddress
+const CHUNK_SIZE: usize = 512;
+
+// The file offset of a LoROM address, None if it isn't mapped to ROM
+pub fn lorom_to_offset(address: u32) -> Option<u32> {
+    let bank = (address >> 16) & 0xFF;
+    let offset = address & 0xFFFF;
+    if offset < 0x8000 || bank == 0x7E || bank == 0x7F {
+        return None;
+    }
+    Some(((bank & 0x7F) << 15) | (offset & 0x7FFF))
+}
+
+// Parse a SNES address as $A0:8000, A08000 or 0xA08000
+pub fn parse_address(s: &str) -> Result<u32, String> {
+    let hex = s
+        .trim_start_matches('$')
+        .trim_start_matches("0x")
+        .replace(':', "");
+    u32::from_str_radix(&hex, 16)
+        .ok()
+        .filter(|a| *a <= 0xFF_FFFF)
+        .ok_or_else(|| format!("can't parse {:?} as an address", s))
+}
+
+// Parse bytes as hex, spaces optional: "A9 00 00" or "A90000"
+pub fn parse_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let hex = s.split_whitespace().collect::<String>();
+    if hex.is_empty() || hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(format!("{:?} isn't a whole number of hex bytes", s));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| format!("{:?} isn't a hex byte", &hex[i..i + 2]))
+        })
+        .collect()
+}
+
+fn rom_offset(address: u32, len: usize) -> Result<u32, Box<dyn Error>> {
+    let offset =
+        lorom_to_offset(address).ok_or_else(|| format!("${:06X} isn't in ROM", address))?;
+    // LoROM banks only map their top half, a patch can't run off the end
+    if (address & 0xFFFF) as usize + len > 0x1_0000 {
+        return Err(format!("{} bytes at ${:06X} crosses a bank", len, address).into());
+    }
+    Ok(offset)
+}
+
+pub fn read_rom(
+    client: &mut SyncClient,
+    address: u32,
+    len: usize,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    client.get_address(rom_offset(address, len)?, len)
+}
+
+pub fn write_rom(client: &mut SyncClient, address: u32, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let offset = rom_offset(address, data.len())?;
+    for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+        client.put_address(offset + (i * CHUNK_SIZE) as u32, chunk)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomPatch {
+    // SNES address
+    pub address: u32,
+    pub original: Vec<u8>,
+    pub patched: Vec<u8>,
+}
+
+pub struct PatchSession<'a> {
+    client: &'a mut SyncClient,
+    patches: Vec<RomPatch>,
+}
+
+impl<'a> PatchSession<'a> {
+    pub fn new(client: &'a mut SyncClient) -> PatchSession<'a> {
+        PatchSession {
+            client,
+            patches: vec![],
+        }
+    }
+
+    // For running other commands while the patches are in
+    pub fn client(&mut self) -> &mut SyncClient {
+        self.client
+    }
+
+    pub fn patches(&self) -> &[RomPatch] {
+        &self.patches
+    }
+
+    pub fn patch(&mut self, address: u32, data: &[u8]) -> Result<&RomPatch, Box<dyn Error>> {
+        let original = read_rom(self.client, address, data.len())?;
+        // Recorded before writing, a write that fails partway through still
+        // gets reverted
+        self.patches.push(RomPatch {
+            address,
+            original,
+            patched: data.to_vec(),
+        });
+        write_rom(self.client, address, data)?;
+        Ok(self.patches.last().unwrap())
+    }
+
+    // Undo the most recent patch
+    pub fn revert_last(&mut self) -> Result<Option<RomPatch>, Box<dyn Error>> {
+        let patch = match self.patches.pop() {
+            Some(patch) => patch,
+            None => return Ok(None),
+        };
+        if let Err(e) = write_rom(self.client, patch.address, &patch.original) {
+            self.patches.push(patch);
+            return Err(e);
+        }
+        Ok(Some(patch))
+    }
+
+    // Undo everything, newest first so overlapping patches come out right
+    pub fn revert(&mut self) -> Result<(), Box<dyn Error>> {
+        while self.revert_last()?.is_some() {}
+        Ok(())
+    }
+
+    // Leave the patches in and forget about them
+    pub fn keep(mut self) -> Vec<RomPatch> {
+        std::mem::take(&mut self.patches)
+    }
+}
+
+impl Drop for PatchSession<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.revert() {
+            eprintln!("couldn't revert {} ROM patches: {}", self.patches.len(), e);
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RomArgs {
+    #[clap(subcommand)]
+    action: RomAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum RomAction {
+    /// Print ROM bytes at a SNES address
+    Read {
+        #[clap(parse(try_from_str = parse_address))]
+        address: u32,
+        len: usize,
+    },
+    /// Patch the ROM, reverting on enter or ctrl-c
+    Patch {
+        /// Patches as address=bytes, like '$A0:8000=A9 00 00'
+        #[clap(required = true)]
+        patches: Vec<String>,
+        /// Leave the patches in, printing how to put the originals back
+        #[clap(long)]
+        keep: bool,
+    },
+}
+
+fn parse_patch(s: &str) -> Result<(u32, Vec<u8>), Box<dyn Error>> {
+    let (address, bytes) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{:?} should be address=bytes", s))?;
+    Ok((parse_address(address.trim())?, parse_bytes(bytes)?))
+}
+
+pub fn run(client: &mut SyncClient, args: RomArgs) -> Result<(), Box<dyn Error>> {
+    match args.action {
+        RomAction::Read { address, len } => {
+            println!("{}", hex(&read_rom(client, address, len)?));
+        }
+        RomAction::Patch { patches, keep } => {
+            // Check everything before touching the ROM
+            let patches = patches
+                .iter()
+                .map(|p| parse_patch(p))
+                .collect::<Result<Vec<_>, _>>()?;
+            // Caught from here on, so a ctrl-c while patching reverts too
+            let revert = if keep {
+                None
+            } else {
+                Some(crate::enter_or_ctrl_c()?)
+            };
+            let mut session = PatchSession::new(client);
+            for (address, data) in &patches {
+                let patch = session.patch(*address, data)?;
+                println!(
+                    "${:06X}: {} -> {}",
+                    patch.address,
+                    hex(&patch.original),
+                    hex(&patch.patched)
+                );
+            }
+            if keep {
+                // Newest first, so overlapping patches come out right
+                let originals = session
+                    .keep()
+                    .iter()
+                    .rev()
+                    .map(|p| format!("'${:06X}={}'", p.address, hex(&p.original)))
+                    .collect::<Vec<_>>();
+                println!("revert with: rom patch --keep {}", originals.join(" "));
+                return Ok(());
+            }
+            println!("patched, press enter or ctrl-c to revert");
+            if let Some(revert) = revert {
+                revert.recv()?;
+            }
+            session.revert()?;
+            println!("reverted");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lorom_offsets() {
+        assert_eq!(lorom_to_offset(0x80_8000), Some(0));
+        assert_eq!(lorom_to_offset(0x80_FFFF), Some(0x7FFF));
+        assert_eq!(lorom_to_offset(0x81_8000), Some(0x8000));
+        assert_eq!(lorom_to_offset(0xA0_8000), Some(0x10_0000));
+        // Banks $00-$7D mirror $80-$FD
+        assert_eq!(lorom_to_offset(0x20_8000), Some(0x10_0000));
+        assert_eq!(lorom_to_offset(0xDF_FFFF), Some(0x2F_FFFF));
+        assert_eq!(lorom_to_offset(0x80_7FFF), None);
+        assert_eq!(lorom_to_offset(0x7E_8000), None);
+        assert_eq!(lorom_to_offset(0x7F_FFFF), None);
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(parse_address("$A0:8000"), Ok(0xA0_8000));
+        assert_eq!(parse_address("A08000"), Ok(0xA0_8000));
+        assert_eq!(parse_address("0xa08000"), Ok(0xA0_8000));
+        assert_eq!(parse_address("$8F:91F8"), Ok(0x8F_91F8));
+        assert!(parse_address("1000000").is_err());
+        assert!(parse_address("A0:80G0").is_err());
+        assert!(parse_address("").is_err());
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(parse_bytes("A9 00 00"), Ok(vec![0xA9, 0x00, 0x00]));
+        assert_eq!(parse_bytes("a90000"), Ok(vec![0xA9, 0x00, 0x00]));
+        assert_eq!(parse_bytes(" 6B "), Ok(vec![0x6B]));
+        assert!(parse_bytes("").is_err());
+        assert!(parse_bytes("A9 0").is_err());
+        assert!(parse_bytes("ZZ").is_err());
+        assert!(parse_bytes("aéa").is_err());
+    }
+
+    #[test]
+    fn patches_stay_in_one_bank() {
+        assert_eq!(rom_offset(0x8F_8000, 0x8000).unwrap(), 0x7_8000);
+        assert_eq!(rom_offset(0x8F_FFFF, 1).unwrap(), 0x7_FFFF);
+        let err = rom_offset(0x8F_FFFF, 2).unwrap_err();
+        assert_eq!(err.to_string(), "2 bytes at $8FFFFF crosses a bank");
+        assert!(rom_offset(0x8F_8000, 0x8001).is_err());
+        assert!(rom_offset(0x7E_8000, 1).is_err());
+        assert!(rom_offset(0x80_1000, 1).is_err());
+    }
+}
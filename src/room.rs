@@ -0,0 +1,211 @@
+//! Where Samus is and what the game is doing.
+//!
+//! The room is the pointer to its header in bank $8F, which doubles as a
+//! unique id. Rooms in `ROOMS` get a name, anything else shows up by pointer.
+
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+use std::fmt;
+
+pub const ROOM_POINTER: u16 = 0x079B;
+pub const AREA_INDEX: u16 = 0x079F;
+pub const LOAD_STATION: u16 = 0x078B;
+pub const GAME_STATE: u16 = 0x0998;
+// Game state value during normal gameplay
+pub const GAMEPLAY: u16 = 0x08;
+
+// The values of the game state byte we care about, runs of states that all
+// mean the same thing to us are folded together.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GameState {
+    Reset,
+    Opening,
+    OptionsMenu,
+    FileSelect,
+    Loading,
+    Gameplay,
+    DoorTransition,
+    Pausing,
+    Paused,
+    Unpausing,
+    Dying,
+    GameOver,
+    ReserveTanks,
+    Intro,
+    CeresEscape,
+    ZebesEscape,
+    Ending,
+    Demo,
+    Other(u8),
+}
+
+impl From<u8> for GameState {
+    fn from(state: u8) -> GameState {
+        match state {
+            0x00 => GameState::Reset,
+            0x01 => GameState::Opening,
+            0x02 => GameState::OptionsMenu,
+            0x04 => GameState::FileSelect,
+            0x05..=0x07 => GameState::Loading,
+            0x08 => GameState::Gameplay,
+            0x09..=0x0B => GameState::DoorTransition,
+            0x0C..=0x0E => GameState::Pausing,
+            0x0F => GameState::Paused,
+            0x10..=0x12 => GameState::Unpausing,
+            0x13..=0x19 => GameState::Dying,
+            0x1A => GameState::GameOver,
+            0x1B => GameState::ReserveTanks,
+            0x1E | 0x1F => GameState::Intro,
+            0x20..=0x25 => GameState::CeresEscape,
+            0x26 => GameState::ZebesEscape,
+            0x27 => GameState::Ending,
+            0x28..=0x2C => GameState::Demo,
+            _ => GameState::Other(state),
+        }
+    }
+}
+
+// Rooms by header pointer
+pub static ROOMS: &[(u16, &str)] = &[
+    (0x91F8, "Landing Site"),
+    (0x92B3, "Gauntlet Entrance"),
+    (0x92FD, "Parlor and Alcatraz"),
+    (0x93AA, "Crateria Power Bomb Room"),
+    (0x93D5, "Crateria Save Room"),
+    (0x93FE, "West Ocean"),
+    (0x948C, "Crateria Kihunter Room"),
+    (0x94FD, "East Ocean"),
+    (0x95D4, "Crateria Tube"),
+    (0x95FF, "The Moat"),
+    (0x965B, "Gauntlet Energy Tank Room"),
+    (0x96BA, "Climb"),
+    (0x975C, "Pit Room"),
+    (0x97B5, "Blue Brinstar Elevator Room"),
+    (0x9804, "Bomb Torizo Room"),
+    (0x9879, "Flyway"),
+    (0x990D, "Terminator Room"),
+    (0x99F9, "Crateria Super Room"),
+    (0x9AD9, "Green Brinstar Main Shaft"),
+    (0x9DC7, "Spore Spawn Room"),
+    (0x9E9F, "Morph Ball Room"),
+    (0xA253, "Red Tower"),
+    (0xA59F, "Kraid Room"),
+    (0xA6A1, "Warehouse Entrance"),
+    (0xA7DE, "Business Center"),
+    (0xA98D, "Crocomire's Room"),
+    (0xB283, "Golden Torizo's Room"),
+    (0xB32E, "Ridley's Room"),
+    (0xCAF6, "Wrecked Ship Main Shaft"),
+    (0xCD13, "Phantoon's Room"),
+    (0xD95E, "Botwoon's Room"),
+    (0xDA60, "Draygon's Room"),
+    (0xDAAE, "Tourian First Room"),
+    (0xDD58, "Mother Brain Room"),
+    (0xDF45, "Ceres Elevator Shaft"),
+    (0xE0B5, "Ceres Ridley's Room"),
+];
+
+pub fn room_name(room: u16) -> Option<&'static str> {
+    ROOMS
+        .iter()
+        .find(|(r, _)| *r == room)
+        .map(|(_, name)| *name)
+}
+
+// Find a room by name, ignoring case, or by hex pointer
+pub fn find_room(name: &str) -> Option<u16> {
+    if let Some((room, _)) = ROOMS.iter().find(|(_, n)| n.eq_ignore_ascii_case(name)) {
+        return Some(*room);
+    }
+    u16::from_str_radix(name.trim_start_matches("0x").trim_start_matches('$'), 16).ok()
+}
+
+pub fn area_from_index(area: u16) -> Option<Area> {
+    if area <= DEBUG as u16 {
+        Some(u8_to_area(area as u8))
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub room: u16,
+    pub area: Option<Area>,
+    pub load_station: u16,
+    pub game_state: GameState,
+}
+
+impl Location {
+    pub fn room_name(&self) -> Option<&'static str> {
+        room_name(self.room)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.room_name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "room ${:04X}", self.room)?,
+        }
+        match self.area {
+            Some(area) => write!(f, " ({:?})", area)?,
+            None => write!(f, " (area ?)")?,
+        }
+        write!(
+            f,
+            ", load station {}, {:?}",
+            self.load_station, self.game_state
+        )
+    }
+}
+
+pub fn get_game_state(client: &mut SyncClient) -> Result<GameState, Box<dyn Error>> {
+    Ok(client.get_address(WRAM + GAME_STATE as u32, 1)?[0].into())
+}
+
+pub fn get_location(client: &mut SyncClient) -> Result<Location, Box<dyn Error>> {
+    Ok(Location {
+        room: get_u16(client, WRAM + ROOM_POINTER as u32)?,
+        area: area_from_index(get_u16(client, WRAM + AREA_INDEX as u32)?),
+        load_station: get_u16(client, WRAM + LOAD_STATION as u32)?,
+        game_state: get_game_state(client)?,
+    })
+}
+
+// Only go ahead if Samus is where the command expects her
+#[derive(clap::Args, Debug)]
+pub struct Conditions {
+    /// Refuse to run unless Samus is in this room, by name or hex pointer
+    #[clap(long, global = true)]
+    in_room: Option<String>,
+    /// Refuse to run unless Samus is in this area
+    #[clap(long, global = true, arg_enum)]
+    in_area: Option<Area>,
+}
+
+impl Conditions {
+    pub fn is_empty(&self) -> bool {
+        self.in_room.is_none() && self.in_area.is_none()
+    }
+
+    pub fn check(&self, client: &mut SyncClient) -> Result<(), Box<dyn Error>> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let location = get_location(client)?;
+        if let Some(room) = &self.in_room {
+            let room = find_room(room).ok_or_else(|| format!("unknown room {:?}", room))?;
+            if location.room != room {
+                return Err(format!("not in the expected room, currently in {}", location).into());
+            }
+        }
+        if let Some(area) = self.in_area {
+            if location.area != Some(area) {
+                return Err(format!("not in {:?}, currently in {}", area, location).into());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! Savestates through the FXPak savestate code.
+//!
+//! The savestate code at $FC0000 compares the input word at $FC2006 with the
+//! save and load shortcuts and does whatever matches. savestate2snes leaves
+//! a hook in place that feeds it the joypad every frame; we instead run the
+//! same routine once with the shortcut we want already loaded, which is the
+//! same as the player pressing it.
+
+use crate::payload;
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const SAVESTATE_CODE: u32 = 0xFC_0000;
+const SAVE_SHORTCUT: u32 = 0xFC_2000;
+const LOAD_SHORTCUT: u32 = 0xFC_2002;
+const SAVESTATE_INPUT: u32 = 0xFC_2006;
+// The savestate code jumps back here, see `savestate2snes()`
+const RETURN_OFFSET: usize = 0x10;
+// Select + R to save, Select + L to load, if nothing is set up yet
+const DEFAULT_SAVE_SHORTCUT: u16 = 0x2010;
+const DEFAULT_LOAD_SHORTCUT: u16 = 0x2020;
+// Where the firmware keeps the state on the SD card
+pub const DEFAULT_REMOTE_STATE: &str = "/sd2snes/states/savestate.sst";
+// How long to give the firmware to finish writing the state out
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+pub const DEFAULT_LIBRARY: &str = "savestates";
+
+#[derive(clap::Args, Debug)]
+pub struct SavestateArgs {
+    /// Directory the named states are kept in
+    #[clap(long, default_value = DEFAULT_LIBRARY)]
+    library: PathBuf,
+    /// Path of the state file on the SD card
+    #[clap(long, default_value = DEFAULT_REMOTE_STATE)]
+    remote: String,
+    #[clap(subcommand)]
+    action: SavestateAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum SavestateAction {
+    /// Save a state, and copy it into the library if a name is given
+    Save { name: Option<String> },
+    /// Load a state, copying it from the library first if a name is given
+    Load { name: Option<String> },
+    /// List the states in the library
+    List,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    Save,
+    Load,
+}
+
+// savestate2snes' routine with the joypad read replaced by `shortcut`. The
+// tail the savestate code returns to stays where it was, the extra work
+// (storing the input and disabling the command) lives after it.
+pub fn trigger_asm(shortcut: u16) -> Vec<u8> {
+    let routine = savestate2snes();
+    let mut r = Vec::new();
+    // php; rep #$30; pha
+    r.extend_from_slice(&routine[..4]);
+    r.extend_from_slice(&lda_immediate_u16(shortcut));
+    let branch_from = r.len() + 2;
+    let branch_to = routine.len();
+    r.extend_from_slice(&bra((branch_to - branch_from) as i8));
+    while r.len() < RETURN_OFFSET {
+        r.extend_from_slice(&nop());
+    }
+    r.extend_from_slice(&routine[RETURN_OFFSET..]);
+    assert_eq!(r.len(), branch_to);
+    r.extend_from_slice(&sta_long(SAVESTATE_INPUT));
+    // disable this command, the savestate code doesn't come back through a
+    // postamble
+    r.extend_from_slice(&stz_absolute(0x2c00));
+    r.extend_from_slice(&jml(SAVESTATE_CODE));
+    r
+}
+
+// Read a shortcut, setting it to our default if none is configured
+fn shortcut(client: &mut SyncClient, address: u32, default: u16) -> Result<u16, Box<dyn Error>> {
+    let shortcut = get_u16(client, address)?;
+    if shortcut != 0 {
+        return Ok(shortcut);
+    }
+    client.put_address(address, &default.to_le_bytes())?;
+    Ok(default)
+}
+
+pub fn trigger(client: &mut SyncClient, trigger: Trigger) -> Result<(), Box<dyn Error>> {
+    let shortcut = match trigger {
+        Trigger::Save => shortcut(client, SAVE_SHORTCUT, DEFAULT_SAVE_SHORTCUT)?,
+        Trigger::Load => shortcut(client, LOAD_SHORTCUT, DEFAULT_LOAD_SHORTCUT)?,
+    };
+    payload::run_programs(client, &[trigger_asm(shortcut)])?;
+    std::thread::sleep(SETTLE_TIME);
+    Ok(())
+}
+
+fn library_path(library: &Path, name: &str) -> PathBuf {
+    library.join(format!("{}.sst", name))
+}
+
+// Save a state, and copy it into `library` if it's named
+pub fn save(
+    client: &mut SyncClient,
+    library: &Path,
+    remote: &str,
+    name: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    trigger(client, Trigger::Save)?;
+    if let Some(name) = name {
+        let data = client.get_file(remote)?;
+        std::fs::create_dir_all(library)?;
+        let path = library_path(library, name);
+        std::fs::write(&path, data)?;
+        println!("saved {}", path.display());
+    }
+    Ok(())
+}
+
+// Load a state, copying it out of `library` first if it's named
+pub fn load(
+    client: &mut SyncClient,
+    library: &Path,
+    remote: &str,
+    name: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(name) = name {
+        let data = std::fs::read(library_path(library, name))?;
+        client.send_file(remote, &data)?;
+    }
+    trigger(client, Trigger::Load)
+}
+
+pub fn run(client: &mut SyncClient, args: SavestateArgs) -> Result<(), Box<dyn Error>> {
+    match args.action {
+        SavestateAction::Save { name } => {
+            save(client, &args.library, &args.remote, name.as_deref())?
+        }
+        SavestateAction::Load { name } => {
+            load(client, &args.library, &args.remote, name.as_deref())?
+        }
+        SavestateAction::List => {
+            let mut names = vec![];
+            if args.library.is_dir() {
+                for entry in std::fs::read_dir(&args.library)? {
+                    let path = entry?.path();
+                    if path.extension().map(|e| e == "sst").unwrap_or(false) {
+                        if let Some(stem) = path.file_stem() {
+                            names.push(stem.to_string_lossy().into_owned());
+                        }
+                    }
+                }
+            }
+            names.sort();
+            for name in names {
+                println!("{}", name);
+            }
+        }
+    }
+    Ok(())
+}
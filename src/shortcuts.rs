@@ -0,0 +1,185 @@
+//! Controller shortcuts, like a practice hack's but on an unmodified ROM.
+//!
+//! Combos come from a TOML file:
+//!
+//! ```toml
+//! [[shortcut]]
+//! combo = "Select+L"
+//! action = "loadout"
+//! file = "presets/kraid.toml"
+//!
+//! [[shortcut]]
+//! combo = "Select+Y"
+//! action = "teleport"
+//! area = "Norfair"
+//! station = "Bubble Mountain"
+//! ```
+//!
+//! A shortcut fires once when its buttons are all held, and again only
+//! after they've been let go. The game still sees the buttons too, so pick
+//! combos that don't do anything where they'll be used.
+
+use crate::input::{get_input, Buttons};
+use crate::payload::{Guard, Payload, PayloadArgs};
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ShortcutAction {
+    /// Apply a loadout on top of the current state
+    Loadout {
+        file: PathBuf,
+    },
+    BlueSuit,
+    SpikeSuit,
+    Teleport {
+        area: Area,
+        station: String,
+    },
+    /// Save a state, into the library if named
+    SaveState {
+        name: Option<String>,
+    },
+    /// Load a state, out of the library if named
+    LoadState {
+        name: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Shortcut {
+    pub combo: String,
+    #[serde(flatten)]
+    pub action: ShortcutAction,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Shortcuts {
+    #[serde(default, rename = "shortcut")]
+    pub shortcuts: Vec<Shortcut>,
+}
+
+impl Shortcuts {
+    pub fn load(path: &Path) -> Result<Shortcuts, Box<dyn Error>> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    // Each shortcut with its parsed combo, checking every combo up front
+    pub fn combos(&self) -> Result<Vec<(Buttons, &ShortcutAction)>, Box<dyn Error>> {
+        self.shortcuts
+            .iter()
+            .map(|s| Ok((Buttons::parse(&s.combo)?, &s.action)))
+            .collect()
+    }
+}
+
+fn apply_loadout(
+    client: &mut SyncClient,
+    file: &Path,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    let loadout = loadout::Loadout::load(file)?;
+    let before = get_samus(client)?;
+    let mut samus = before.clone();
+    loadout.apply(&mut samus)?;
+    let violations = samus.validate();
+    if violations
+        .iter()
+        .any(|v| v.severity == validate::Severity::Error)
+    {
+        for violation in &violations {
+            eprintln!("{}", violation);
+        }
+        return Err(format!("not applying {}, it has errors", file.display()).into());
+    }
+    let diff = diff::SamusDiff::new(&before, &samus);
+    if diff.is_empty() {
+        return Ok(());
+    }
+    Payload::run_block(client, &diff.to_asm(true), payload_args)
+}
+
+pub fn run_action(
+    client: &mut SyncClient,
+    action: &ShortcutAction,
+    library: &Path,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    match action {
+        ShortcutAction::Loadout { file } => apply_loadout(client, file, payload_args),
+        ShortcutAction::BlueSuit => Payload::run_block(client, &blue_suit_asm(), payload_args),
+        ShortcutAction::SpikeSuit => Payload::run_block(client, &spike_suit_asm(), payload_args),
+        ShortcutAction::Teleport { area, station } => {
+            let station = teleport::find_station(*area, station)
+                .ok_or_else(|| format!("no station {:?} in {:?}", station, area))?;
+            // Same as the teleport command, always guarded
+            let payload_args = PayloadArgs {
+                guard: match payload_args.guard {
+                    Guard::None => Guard::Skip,
+                    guard => guard,
+                },
+                ..payload_args
+            };
+            Payload::run_block(client, &teleport::teleport_asm(&station), payload_args)
+        }
+        ShortcutAction::SaveState { name } => savestate::save(
+            client,
+            library,
+            savestate::DEFAULT_REMOTE_STATE,
+            name.as_deref(),
+        ),
+        ShortcutAction::LoadState { name } => savestate::load(
+            client,
+            library,
+            savestate::DEFAULT_REMOTE_STATE,
+            name.as_deref(),
+        ),
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ShortcutsArgs {
+    /// TOML file mapping combos to actions
+    config: PathBuf,
+    /// Milliseconds between controller polls
+    #[clap(long, default_value = "16")]
+    interval: u64,
+    /// Directory named savestates are kept in
+    #[clap(long, default_value = savestate::DEFAULT_LIBRARY)]
+    library: PathBuf,
+}
+
+pub fn run(
+    client: &mut SyncClient,
+    args: ShortcutsArgs,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    let shortcuts = Shortcuts::load(&args.config)?;
+    let combos = shortcuts.combos()?;
+    if combos.is_empty() {
+        return Err(format!("no shortcuts in {}", args.config.display()).into());
+    }
+    for (buttons, action) in &combos {
+        println!("{:20} {:?}", buttons.to_string(), action);
+    }
+    println!("listening, ctrl-c to stop");
+    let mut last = Buttons::default();
+    loop {
+        let held = get_input(client)?.held;
+        for (buttons, action) in &combos {
+            if held.contains(*buttons) && !last.contains(*buttons) {
+                // A bad shortcut shouldn't take the rest down with it
+                if let Err(e) = run_action(client, action, &args.library, payload_args) {
+                    eprintln!("{}: {}", buttons, e);
+                }
+            }
+        }
+        last = held;
+        std::thread::sleep(Duration::from_millis(args.interval));
+    }
+}
@@ -0,0 +1,118 @@
+//! Teleporting to load stations.
+//!
+//! A load station is where the game puts Samus when a save is loaded: a save
+//! room, an elevator or one of the debug spots. Setting the area and station
+//! then switching the game state to "loading game data" makes the game load
+//! from there, the same way the practice hack teleports.
+
+use crate::payload::{Guard, Payload, PayloadArgs};
+use crate::room::{AREA_INDEX, GAME_STATE, LOAD_STATION};
+use crate::usb2snes::SyncClient;
+use crate::*;
+use std::error::Error;
+
+// Game state that loads the room for the current area and load station
+const LOADING_GAME_DATA: u16 = 0x06;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Station {
+    pub area: Area,
+    pub index: u16,
+    pub name: &'static str,
+}
+
+const fn station(area: Area, index: u16, name: &'static str) -> Station {
+    Station { area, index, name }
+}
+
+pub static STATIONS: &[Station] = &[
+    station(Area::Crateria, 0, "Ship"),
+    station(Area::Crateria, 1, "Parlor"),
+    station(Area::Brinstar, 0, "Pink Spore Spawn"),
+    station(Area::Brinstar, 1, "Green Shaft"),
+    station(Area::Brinstar, 2, "Etecoons"),
+    station(Area::Brinstar, 3, "Kraid"),
+    station(Area::Brinstar, 4, "Red Tower"),
+    station(Area::Norfair, 0, "Grapple"),
+    station(Area::Norfair, 1, "Bubble Mountain"),
+    station(Area::Norfair, 2, "Rising Tide"),
+    station(Area::Norfair, 3, "Frog Speedway"),
+    station(Area::Norfair, 4, "Lower Norfair Elevator"),
+    station(Area::Norfair, 5, "Lower Norfair Entrance"),
+    station(Area::WreckedShip, 0, "Wrecked Ship"),
+    station(Area::Maridia, 0, "Tube"),
+    station(Area::Maridia, 1, "Elevator"),
+    station(Area::Maridia, 2, "Red Fish"),
+    station(Area::Maridia, 3, "Draygon"),
+    station(Area::Tourian, 0, "Mother Brain"),
+    station(Area::Tourian, 1, "Entrance"),
+    station(Area::Ceres, 0, "Ceres"),
+];
+
+// Find a station in `area` by name, ignoring case, or by index. Indices
+// missing from the table are allowed since hacks add their own.
+pub fn find_station(area: Area, name: &str) -> Option<Station> {
+    let in_area = || STATIONS.iter().filter(move |s| s.area == area);
+    if let Ok(index) = name.parse::<u16>() {
+        return Some(
+            in_area()
+                .find(|s| s.index == index)
+                .copied()
+                .unwrap_or(Station {
+                    area,
+                    index,
+                    name: "unknown",
+                }),
+        );
+    }
+    in_area()
+        .find(|s| s.name.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+pub fn teleport_asm(station: &Station) -> Vec<u8> {
+    let mut r = Vec::new();
+    r.extend_from_slice(&lda_immediate_u16(area_to_u8(&station.area) as u16));
+    r.extend_from_slice(&sta_absolute(AREA_INDEX));
+    r.extend_from_slice(&lda_immediate_u16(station.index));
+    r.extend_from_slice(&sta_absolute(LOAD_STATION));
+    r.extend_from_slice(&lda_immediate_u16(LOADING_GAME_DATA));
+    r.extend_from_slice(&sta_absolute(GAME_STATE));
+    r
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TeleportArgs {
+    /// Area to teleport to, or list the stations in it with no station
+    #[clap(arg_enum)]
+    area: Area,
+    /// Station name or index
+    station: Option<String>,
+}
+
+pub fn run(
+    client: &mut SyncClient,
+    args: TeleportArgs,
+    payload_args: PayloadArgs,
+) -> Result<(), Box<dyn Error>> {
+    let name = match args.station {
+        Some(name) => name,
+        None => {
+            for station in STATIONS.iter().filter(|s| s.area == args.area) {
+                println!("{:2} {}", station.index, station.name);
+            }
+            return Ok(());
+        }
+    };
+    let station = find_station(args.area, &name)
+        .ok_or_else(|| format!("no station {:?} in {:?}", name, args.area))?;
+    let mut payload = Payload::from_args(payload_args);
+    // Kicking off a load in the middle of another transition goes badly, so
+    // this is always guarded
+    payload.set_guard(match payload_args.guard {
+        Guard::None => Guard::Skip,
+        guard => guard,
+    });
+    payload.push(&teleport_asm(&station));
+    payload.run(client)
+}
@@ -385,7 +385,12 @@ impl SyncClient {
     }
     // TODO: refactor this and get_cmd
     pub fn get_cmd_header_byte(&mut self) -> Result<u8, Box<dyn Error>> {
-        let address = 0x2c00;
+        self.get_cmd_byte(0)
+    }
+
+    // One byte of the CMD space, `offset` bytes in
+    pub fn get_cmd_byte(&mut self, offset: u16) -> Result<u8, Box<dyn Error>> {
+        let address = 0x2c00 + offset as u32;
         let cmd_len = 1;
         self.send_command_with_space(
             Command::GetAddress,
@@ -0,0 +1,376 @@
+//! Sanity checks for Samus edits before they are written to the game.
+
+use crate::*;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    // Odd but the game copes with it
+    Warning,
+    // Likely to glitch or crash the game
+    Error,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rule {
+    EquippedItemNotCollected(Item),
+    EquippedBeamNotCollected(Beam),
+    SpazerAndPlasma,
+    HpOverMax,
+    ReserveOverMax,
+    MissilesOverMax,
+    SupersOverMax,
+    PBsOverMax,
+    MaxHpNotTanks,
+    MaxReserveNotTanks,
+    ReservesWithoutMode,
+    MaxAmmoNotPacks(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub severity: Severity,
+    pub rule: Rule,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        let message = match self.rule {
+            Rule::EquippedItemNotCollected(item) => {
+                format!("{:?} is equipped but not collected", item)
+            }
+            Rule::EquippedBeamNotCollected(beam) => {
+                format!("{:?} is equipped but not collected", beam)
+            }
+            Rule::SpazerAndPlasma => "Spazer and Plasma are equipped together".to_string(),
+            Rule::HpOverMax => "HP is above max HP".to_string(),
+            Rule::ReserveOverMax => "reserve HP is above max reserve HP".to_string(),
+            Rule::MissilesOverMax => "missiles are above max missiles".to_string(),
+            Rule::SupersOverMax => "supers are above max supers".to_string(),
+            Rule::PBsOverMax => "power bombs are above max power bombs".to_string(),
+            Rule::MaxHpNotTanks => "max HP is not 99 plus a multiple of 100".to_string(),
+            Rule::MaxReserveNotTanks => "max reserve HP is not a multiple of 100".to_string(),
+            Rule::ReservesWithoutMode => {
+                "reserve tanks are set but the reserve mode is none".to_string()
+            }
+            Rule::MaxAmmoNotPacks(field) => format!("{} is not a multiple of 5", field),
+        };
+        write!(f, "{}: {}", severity, message)
+    }
+}
+
+// Which violations `Samus::fix` is allowed to repair
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fixes {
+    // Clamp HP, reserves and ammo to their maximums
+    pub clamp: bool,
+    // Unequip Spazer when Plasma is equipped, like the game does
+    pub drop_conflicting_beams: bool,
+    // Mark everything equipped as collected. Unknown bits are left alone,
+    // hacks don't necessarily pair them up like the vanilla ones.
+    pub collect_equipped: bool,
+}
+
+impl Fixes {
+    pub fn all() -> Fixes {
+        Fixes {
+            clamp: true,
+            drop_conflicting_beams: true,
+            collect_equipped: true,
+        }
+    }
+}
+
+impl Samus {
+    pub fn validate(&self) -> Vec<Violation> {
+        let mut r = vec![];
+        let mut push = |severity, rule| r.push(Violation { severity, rule });
+
+        let mut items: Vec<_> = self
+            .equipped_items
+            .difference(&self.collected_items)
+            .filter(|item| !item.is_unknown())
+            .collect();
+        items.sort();
+        for item in items {
+            push(Severity::Error, Rule::EquippedItemNotCollected(*item));
+        }
+        let mut beams: Vec<_> = self
+            .equipped_beams
+            .difference(&self.collected_beams)
+            .filter(|beam| !beam.is_unknown())
+            .collect();
+        beams.sort();
+        for beam in beams {
+            push(Severity::Error, Rule::EquippedBeamNotCollected(*beam));
+        }
+        if self.equipped_beams.contains(&Beam::Spazer)
+            && self.equipped_beams.contains(&Beam::Plasma)
+        {
+            push(Severity::Error, Rule::SpazerAndPlasma);
+        }
+
+        if self.hp > self.max_hp {
+            push(Severity::Error, Rule::HpOverMax);
+        }
+        if self.reserve_hp > self.max_reserve_hp {
+            push(Severity::Error, Rule::ReserveOverMax);
+        }
+        if self.missiles > self.max_missiles {
+            push(Severity::Warning, Rule::MissilesOverMax);
+        }
+        if self.supers > self.max_supers {
+            push(Severity::Warning, Rule::SupersOverMax);
+        }
+        if self.pbs > self.max_pbs {
+            push(Severity::Warning, Rule::PBsOverMax);
+        }
+
+        // Pickups only ever hand out whole tanks and packs
+        if self.max_hp < 99 || !(self.max_hp - 99).is_multiple_of(100) {
+            push(Severity::Warning, Rule::MaxHpNotTanks);
+        }
+        if !self.max_reserve_hp.is_multiple_of(100) {
+            push(Severity::Warning, Rule::MaxReserveNotTanks);
+        }
+        if self.max_reserve_hp > 0 && self.reserve_mode == ReserveMode::None {
+            push(Severity::Warning, Rule::ReservesWithoutMode);
+        }
+        for (field, max) in [
+            ("max missiles", self.max_missiles),
+            ("max supers", self.max_supers),
+            ("max power bombs", self.max_pbs),
+        ] {
+            if !max.is_multiple_of(5) {
+                push(Severity::Warning, Rule::MaxAmmoNotPacks(field));
+            }
+        }
+        r
+    }
+
+    // Repair what `fixes` allows, returns whatever is still wrong afterwards
+    pub fn fix(&mut self, fixes: Fixes) -> Vec<Violation> {
+        if fixes.collect_equipped {
+            self.collected_items.extend(
+                self.equipped_items
+                    .iter()
+                    .copied()
+                    .filter(|item| !item.is_unknown()),
+            );
+            self.collected_beams.extend(
+                self.equipped_beams
+                    .iter()
+                    .copied()
+                    .filter(|beam| !beam.is_unknown()),
+            );
+        }
+        if fixes.drop_conflicting_beams
+            && self.equipped_beams.contains(&Beam::Plasma)
+            && self.equipped_beams.contains(&Beam::Spazer)
+        {
+            self.equipped_beams.remove(&Beam::Spazer);
+        }
+        if fixes.clamp {
+            self.hp = self.hp.min(self.max_hp);
+            self.reserve_hp = self.reserve_hp.min(self.max_reserve_hp);
+            self.missiles = self.missiles.min(self.max_missiles);
+            self.supers = self.supers.min(self.max_supers);
+            self.pbs = self.pbs.min(self.max_pbs);
+        }
+        self.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two tanks, a reserve and some ammo, nothing wrong with it
+    fn samus() -> Samus {
+        Samus {
+            hp: 299,
+            max_hp: 299,
+            reserve_hp: 100,
+            max_reserve_hp: 100,
+            reserve_mode: ReserveMode::Auto,
+            missiles: 10,
+            max_missiles: 10,
+            supers: 5,
+            max_supers: 5,
+            pbs: 5,
+            max_pbs: 5,
+            ..Samus::default()
+        }
+    }
+
+    fn rules(samus: &Samus) -> Vec<(Severity, Rule)> {
+        samus
+            .validate()
+            .into_iter()
+            .map(|v| (v.severity, v.rule))
+            .collect()
+    }
+
+    // Everything `edit` breaks, starting from a valid Samus
+    fn broken(edit: impl FnOnce(&mut Samus)) -> Vec<(Severity, Rule)> {
+        let mut samus = samus();
+        edit(&mut samus);
+        rules(&samus)
+    }
+
+    #[test]
+    fn valid() {
+        assert_eq!(rules(&samus()), vec![]);
+    }
+
+    #[test]
+    fn equipped_not_collected() {
+        assert_eq!(
+            broken(|s| {
+                s.equipped_items.insert(Item::Varia);
+            }),
+            vec![(Severity::Error, Rule::EquippedItemNotCollected(Item::Varia))]
+        );
+        assert_eq!(
+            broken(|s| {
+                s.equipped_beams.insert(Beam::Ice);
+            }),
+            vec![(Severity::Error, Rule::EquippedBeamNotCollected(Beam::Ice))]
+        );
+        // Unknown bits aren't held to it
+        assert_eq!(
+            broken(|s| {
+                s.equipped_items.insert(Item::Unknown(4));
+            }),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn spazer_and_plasma() {
+        assert_eq!(
+            broken(|s| {
+                s.collected_beams.extend([Beam::Spazer, Beam::Plasma]);
+                s.equipped_beams.extend([Beam::Spazer, Beam::Plasma]);
+            }),
+            vec![(Severity::Error, Rule::SpazerAndPlasma)]
+        );
+    }
+
+    #[test]
+    fn over_max() {
+        assert_eq!(
+            broken(|s| s.hp = 300),
+            vec![(Severity::Error, Rule::HpOverMax)]
+        );
+        assert_eq!(
+            broken(|s| s.reserve_hp = 101),
+            vec![(Severity::Error, Rule::ReserveOverMax)]
+        );
+        assert_eq!(
+            broken(|s| s.missiles = 11),
+            vec![(Severity::Warning, Rule::MissilesOverMax)]
+        );
+        assert_eq!(
+            broken(|s| s.supers = 6),
+            vec![(Severity::Warning, Rule::SupersOverMax)]
+        );
+        assert_eq!(
+            broken(|s| s.pbs = 6),
+            vec![(Severity::Warning, Rule::PBsOverMax)]
+        );
+    }
+
+    #[test]
+    fn not_whole_pickups() {
+        assert_eq!(
+            broken(|s| {
+                s.max_hp = 250;
+                s.hp = 250;
+            }),
+            vec![(Severity::Warning, Rule::MaxHpNotTanks)]
+        );
+        assert_eq!(
+            broken(|s| {
+                s.max_hp = 50;
+                s.hp = 50;
+            }),
+            vec![(Severity::Warning, Rule::MaxHpNotTanks)]
+        );
+        assert_eq!(
+            broken(|s| s.max_reserve_hp = 150),
+            vec![(Severity::Warning, Rule::MaxReserveNotTanks)]
+        );
+        assert_eq!(
+            broken(|s| s.max_supers = 7),
+            vec![(Severity::Warning, Rule::MaxAmmoNotPacks("max supers"))]
+        );
+    }
+
+    #[test]
+    fn reserves_without_mode() {
+        assert_eq!(
+            broken(|s| s.reserve_mode = ReserveMode::None),
+            vec![(Severity::Warning, Rule::ReservesWithoutMode)]
+        );
+    }
+
+    #[test]
+    fn fix_clamps() {
+        let mut s = samus();
+        s.hp = 1000;
+        s.reserve_hp = 1000;
+        s.missiles = 1000;
+        s.supers = 1000;
+        s.pbs = 1000;
+        assert_eq!(s.fix(Fixes::all()), vec![]);
+        assert_eq!(
+            (s.hp, s.reserve_hp, s.missiles, s.supers, s.pbs),
+            (299, 100, 10, 5, 5)
+        );
+    }
+
+    #[test]
+    fn fix_drops_spazer() {
+        let mut s = samus();
+        s.collected_beams.extend([Beam::Spazer, Beam::Plasma]);
+        s.equipped_beams.extend([Beam::Spazer, Beam::Plasma]);
+        assert_eq!(s.fix(Fixes::all()), vec![]);
+        assert_eq!(s.equipped_beams, HashSet::from([Beam::Plasma]));
+        assert_eq!(s.collected_beams.len(), 2);
+    }
+
+    #[test]
+    fn fix_collects_equipped() {
+        let mut s = samus();
+        s.equipped_items.extend([Item::Varia, Item::Unknown(4)]);
+        s.equipped_beams.insert(Beam::Wave);
+        assert_eq!(s.fix(Fixes::all()), vec![]);
+        assert_eq!(s.collected_items, HashSet::from([Item::Varia]));
+        assert_eq!(s.collected_beams, HashSet::from([Beam::Wave]));
+    }
+
+    #[test]
+    fn fix_only_what_is_allowed() {
+        let mut s = samus();
+        s.hp = 1000;
+        s.equipped_items.insert(Item::Varia);
+        let left = s.fix(Fixes {
+            clamp: true,
+            drop_conflicting_beams: false,
+            collect_equipped: false,
+        });
+        assert_eq!(s.hp, 299);
+        assert_eq!(
+            left,
+            vec![Violation {
+                severity: Severity::Error,
+                rule: Rule::EquippedItemNotCollected(Item::Varia),
+            }]
+        );
+    }
+}